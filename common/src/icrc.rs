@@ -0,0 +1,103 @@
+use ic_cdk::export::candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::api::call::call;
+use serde::Serialize;
+
+use std::convert::TryFrom;
+
+/// ICRC-1 account, owner principal with optional 32 byte subaccount
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+impl From<Principal> for Account {
+    fn from(owner: Principal) -> Self {
+        Account { owner, subaccount: None }
+    }
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Vec<u8>>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Vec<u8>>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+fn nat_to_u64(n: &Nat) -> Result<u64, String> {
+    u64::try_from(&n.0).map_err(|_| format!("Value {} does not fit in u64", n))
+}
+
+/// Returns the transfer fee of ICRC-1 token canister
+pub async fn call_icrc1_fee(canister: Principal) -> Result<u64, String> {
+    let (fee,): (Nat,) = call(canister, "icrc1_fee", ())
+        .await
+        .map_err(|(_, s)| format!("Error invoking token ledger, {}", &s))?;
+
+    nat_to_u64(&fee)
+}
+
+/// Sends tokens from canister account, returns block index
+pub async fn call_icrc1_transfer(canister: Principal, args: &TransferArg) -> Result<u64, String> {
+    let (res,): (Result<Nat, TransferError>,) = call(canister, "icrc1_transfer", (args,))
+        .await
+        .map_err(|(_, s)| format!("Error invoking token ledger, {}", &s))?;
+
+    //Duplicate means transfer with the same args and created_at_time is already in ledger
+    let block = match res {
+        Ok(block) | Err(TransferError::Duplicate { duplicate_of: block }) => block,
+        Err(e) => return Err(format!("Transfer failed, {:?}", e)),
+    };
+
+    nat_to_u64(&block)
+}
+
+/// Moves approved tokens on behalf of args.from, returns block index
+pub async fn call_icrc2_transfer_from(canister: Principal, args: &TransferFromArgs) -> Result<u64, String> {
+    let (res,): (Result<Nat, TransferFromError>,) = call(canister, "icrc2_transfer_from", (args,))
+        .await
+        .map_err(|(_, s)| format!("Error invoking token ledger, {}", &s))?;
+
+    let block = res.map_err(|e| format!("Transfer from failed, {:?}", e))?;
+
+    nat_to_u64(&block)
+}
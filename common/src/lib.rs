@@ -1,6 +1,7 @@
 pub mod rc_bytes;
 mod types;
 mod icp_ledger;
+mod icrc;
 
 pub mod account_identifier;
 
@@ -11,6 +12,7 @@ use sha2::Digest;
 
 pub use types::*;
 pub use icp_ledger::*;
+pub use icrc::*;
 
 pub static SUB_ACCOUNT_ZERO: Subaccount = Subaccount([0; 32]);
 pub static ACCOUNT_DOMAIN_SEPERATOR: &[u8] = b"\x0Aaccount-id";
@@ -47,7 +47,15 @@ pub enum Operation {
     mint,
     burn,
     purchase,
-    transfer
+    transfer,
+    update_metadata,
+    set_property,
+    recover,
+    grant_role,
+    revoke_role,
+    propose_owner,
+    accept_ownership,
+    renounce_ownership
 }
 
 impl Default for Operation {
@@ -81,11 +89,27 @@ pub struct TransactionNotification {
     pub to_subaccount: Option<Subaccount>,
 }
 
+#[derive(Clone, CandidType, Deserialize, Serialize)]
+pub struct RoyaltyShare {
+    pub account: Principal,
+    /// Part of the sale price, in the same 1/100000 unit as creators_fee
+    pub fee: u64,
+}
+
 #[derive(Clone, CandidType, Deserialize, Serialize)]
 pub struct TransactionResponse {
     pub block: u64,
     pub creators_fee: u64,
-    pub seller: Principal
+    pub seller: Principal,
+    /// Split of creators_fee between royalty recipients
+    #[serde(default)]
+    pub royalties: Vec<RoyaltyShare>,
+    /// Listing price, paid amount above it is returned to the buyer
+    #[serde(default)]
+    pub price: u64,
+    /// Market fee in 1/100000 of price, from fee configuration of token canister
+    #[serde(default)]
+    pub market_fee: u64,
 }
 
 #[derive(Clone, CandidType, Deserialize,Serialize)]
@@ -104,5 +128,8 @@ pub struct SendArgs {
     pub from_subaccount: Option<Subaccount>,
     pub to: String,
     pub created_at_time: Option<TimeStamp>,
+    /// Owner of account `to`, required by ICRC-1 transfers which cannot target an account id
+    #[serde(default)]
+    pub to_account: Option<crate::icrc::Account>,
 }
 
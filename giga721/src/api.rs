@@ -8,9 +8,9 @@ use ic_cdk_macros::{query, update};
 
 use ic_cdk::export::candid::Principal;
 
-use crate::token::{Token, TokenDesc, TokenOwner};
+use crate::token::{PauseStatus, PauseSwitch, Token, TokenDesc, TokenOwner};
 
-use crate::guards::{owner_guard, not_paused};
+use crate::guards::{admin_guard, fee_manager_guard, pauser_guard, transfers_not_paused};
 
 #[query]
 fn get_ledger_canister() -> Option<Principal> {
@@ -30,20 +30,40 @@ fn is_paused() -> bool {
     State::get().borrow().is_paused
 }
 
-#[update(guard="owner_guard")]
+#[query]
+fn pause_status() -> PauseStatus {
+    State::get().borrow().pause_status()
+}
+
+#[update(guard="pauser_guard")]
 fn set_tx_enabled(enabled: bool) -> bool {
     Marketplace::get().borrow_mut().tx_enabled = enabled;
 
     return true;
 }
 
-#[update(guard="owner_guard")]
+#[update(guard="pauser_guard")]
 fn set_paused(paused: bool) -> bool {
     State::get().borrow_mut().is_paused = paused;
 
     return true;
 }
 
+//Pauses or resumes transfers, marketplace or minting alone, all is the same as set_paused
+#[update(guard="pauser_guard")]
+fn set_pause(switch: PauseSwitch, paused: bool) -> bool {
+    State::get().borrow_mut().set_pause(switch, paused);
+
+    return true;
+}
+
+#[update(guard="pauser_guard")]
+fn set_pause_reason(reason: Option<String>) -> bool {
+    State::get().borrow_mut().pause_reason = reason;
+
+    return true;
+}
+
 #[query]
 fn name() -> String {
     State::get().borrow().name.clone()
@@ -64,8 +84,9 @@ fn icon_url() -> String {
 }
 
 #[query]
-fn owner() -> Principal {
-    State::get().borrow().owner.unwrap()
+fn owner() -> Option<Principal> {
+    //Contract has no owner after renounce_ownership
+    State::get().borrow().owner
 }
 
 #[query]
@@ -77,7 +98,7 @@ fn creators_fee() -> u128 {
     Marketplace::get().borrow().creators_fee
 }
 
-#[update(guard="owner_guard")]
+#[update(guard="fee_manager_guard")]
 fn set_creators_fee(fee: u128) -> bool {
     MARKETPLACE.with(|x| x.borrow_mut().creators_fee = fee);    
 
@@ -89,7 +110,7 @@ fn creators_address() -> Option<Principal> {
     Marketplace::get().borrow().creators_address
 }
 
-#[update(guard="owner_guard")]
+#[update(guard="fee_manager_guard")]
 fn set_creators_address(creator: Principal) -> bool {
     MARKETPLACE.with(|x| x.borrow_mut().creators_address = Some(creator));    
 
@@ -135,7 +156,11 @@ fn data_of(token_id: u128) -> TokenDesc {
 
 #[query]
 fn metadata() -> Vec<Token> {
-    STATE.with(|x| x.borrow().tokens.values().map(|x| (*x).clone()).collect())
+    STATE.with(|x| {
+        let state = x.borrow();
+        if state.metadata_hidden { return state.tokens.keys().map(|x| state.placeholder_of(*x)).collect(); }
+        state.tokens.values().map(|x| (*x).clone()).collect()
+    })
 }
 
 
@@ -151,19 +176,13 @@ fn tokens() -> Vec<u32> {
     })
 }
 
-#[update(guard="owner_guard")]
-fn set_owner(owner: Principal) -> bool {
-    STATE.with(|x| x.borrow_mut().owner = Some(owner));    
-
-    return true;
-}
-#[update(guard="owner_guard")]
+#[update(guard="admin_guard")]
 fn set_description(description: String) -> bool {
     STATE.with(|x| x.borrow_mut().description = description);    
 
     return true;
 }
-#[update(guard="owner_guard")]
+#[update(guard="admin_guard")]
 fn set_icon_url(icon_url: String) -> bool {
     STATE.with(|x| x.borrow_mut().icon_url = icon_url);    
 
@@ -171,12 +190,20 @@ fn set_icon_url(icon_url: String) -> bool {
 }
 
 
-#[update(guard="owner_guard")]
+#[update(guard="admin_guard")]
 async fn add_genesis_record() -> Result<u64, String> {
     Ok(LEDGER.with(|x| x.borrow_mut().add_genesis_record(caller())))
 }
 
-#[update(guard="owner_guard")]
+//Sets how long reserve locks a listing, in nanoseconds
+#[update(guard="admin_guard")]
+fn set_reservation_window(window: u64) -> bool {
+    Marketplace::get().borrow_mut().reservation_window = window;
+
+    return true;
+}
+
+#[update(guard="admin_guard")]
 fn set_ledger_canister(ledger: Principal) -> bool {
     Marketplace::get().borrow_mut().ledger_canister = Some(ledger);
 
@@ -184,7 +211,7 @@ fn set_ledger_canister(ledger: Principal) -> bool {
 }
 
 //Used to transfer @token_id from owner to @to
-#[update]
+#[update(guard="transfers_not_paused")]
 async fn transfer_to(to: Principal, token_id: u128) -> bool {
     let res = STATE.with(|x| x.borrow_mut().transfer(caller(), to, token_id as u32));
 
@@ -194,6 +221,40 @@ async fn transfer_to(to: Principal, token_id: u128) -> bool {
     }
 }
 
+#[query]
+fn is_transferable(token_id: u32) -> bool {
+    STATE.with(|x| x.borrow().check_transferable(token_id).is_ok())
+}
+
+//Makes whole collection non-transferable, tokens can still be minted and burned
+#[update(guard="admin_guard")]
+fn set_soulbound(soulbound: bool) -> bool {
+    STATE.with(|x| x.borrow_mut().soulbound = soulbound);
+    Marketplace::get().borrow_mut().remove_untransferable();
+
+    true
+}
+
+#[update(guard="admin_guard")]
+fn set_token_soulbound(token_id: u32, soulbound: bool) -> bool {
+    STATE.with(|x| {
+        let mut state = x.borrow_mut();
+        match soulbound {
+            true => state.soulbound_tokens.insert(token_id),
+            false => state.soulbound_tokens.remove(&token_id),
+        }
+    });
+    Marketplace::get().borrow_mut().remove_untransferable();
+
+    true
+}
+
+//Moves non-transferable token to new holder, recorded in ledger
+#[update(guard="admin_guard")]
+fn recover_token(token_id: u32, to: Principal) -> Result<u64, String> {
+    STATE.with(|x| x.borrow_mut().recover(caller(), to, token_id))
+}
+
 
 
 // #[update]
@@ -0,0 +1,117 @@
+use ic_cdk::export::candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::{query, update};
+use serde::Serialize;
+
+use crate::marketplace::Marketplace;
+use crate::token::State;
+use crate::guards::{fee_manager_guard};
+
+/// Whole sale price in the 1/100000 fee unit used by creators_fee and market fee
+pub const FEE_DENOMINATOR: u64 = 100000;
+
+/// Market fee for sales with price at or above min_price
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize, Serialize)]
+pub struct FeeTier {
+    pub min_price: u64,
+    pub fee: u64,
+}
+
+/// Marketplace fee settings, fees are in 1/100000 of the sale price
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize, Serialize)]
+pub struct FeeConfig {
+    pub market_fee: u64,
+    /// Receives market fee of sales settled by this canister (ICRC-1 listings)
+    pub market_address: Option<Principal>,
+    /// Price based tiers, tier with the highest matching min_price replaces market_fee
+    pub tiers: Vec<FeeTier>,
+    /// Fee for buyers holding at least holder_min_tokens tokens, used when lower than price based fee
+    pub holder_fee: Option<u64>,
+    pub holder_min_tokens: u32,
+}
+
+impl Default for FeeConfig {
+    fn default() -> Self {
+        Self {
+            market_fee: 2500,
+            market_address: None,
+            tiers: Vec::default(),
+            holder_fee: None,
+            holder_min_tokens: 1,
+        }
+    }
+}
+
+impl FeeConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let too_high = self.market_fee > FEE_DENOMINATOR
+            || self.tiers.iter().any(|x| x.fee > FEE_DENOMINATOR)
+            || self.holder_fee.is_some_and(|x| x > FEE_DENOMINATOR);
+
+        if too_high { return Err(String::from("Market fee cannot exceed 100% of the sale price")); }
+
+        Ok(())
+    }
+
+    /// Returns market fee for a sale with given price to buyer holding given number of tokens
+    pub fn fee_for(&self, price: u64, holdings: u32) -> u64 {
+        let fee = self.tiers.iter()
+            .filter(|x| x.min_price <= price)
+            .max_by_key(|x| x.min_price)
+            .map_or(self.market_fee, |x| x.fee);
+
+        match self.holder_fee {
+            Some(holder_fee) if holdings >= self.holder_min_tokens => fee.min(holder_fee),
+            _ => fee,
+        }
+    }
+}
+
+/// Returns number of tokens held by buyer
+pub fn holdings(buyer: Principal) -> u32 {
+    State::get().borrow().owners.get(&buyer).map_or(0, |x| x.len() as u32)
+}
+
+#[query]
+fn fee_config() -> FeeConfig {
+    Marketplace::get().borrow().fees.clone()
+}
+
+#[update(guard="fee_manager_guard")]
+fn set_fee_config(config: FeeConfig) -> Result<(), String> {
+    config.validate()?;
+    Marketplace::get().borrow_mut().fees = config;
+
+    Ok(())
+}
+
+//Returns market fee that would be charged to buyer for given price, in 1/100000 of price
+#[query]
+fn market_fee_for(price: u64, buyer: Principal) -> u64 {
+    Marketplace::get().borrow().fees.fee_for(price, holdings(buyer))
+}
+
+#[cfg(test)]
+mod test {
+use super::*;
+
+    #[test]
+    fn test_fee_tiers() {
+        let config = FeeConfig {
+            market_fee: 2500,
+            market_address: None,
+            tiers: vec![
+                FeeTier { min_price: 1000000000, fee: 2000 },
+                FeeTier { min_price: 10000000000, fee: 1000 },
+            ],
+            holder_fee: Some(1500),
+            holder_min_tokens: 2,
+        };
+
+        assert_eq!(config.validate(), Ok(()));
+        assert_eq!(config.fee_for(100000000, 0), 2500);
+        assert_eq!(config.fee_for(1000000000, 0), 2000);
+        assert_eq!(config.fee_for(50000000000, 0), 1000);
+        assert_eq!(config.fee_for(100000000, 2), 1500);
+        assert_eq!(config.fee_for(50000000000, 2), 1000);
+    }
+}
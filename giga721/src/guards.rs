@@ -1,5 +1,6 @@
 
-use crate::token::State;
+use crate::token::{PauseSwitch, State};
+use crate::roles::Role;
 use ic_cdk::{caller};
 
 #[inline(always)]
@@ -13,10 +14,55 @@ pub fn owner_guard() -> Result<(), String> {
     }
 }
 
+fn role_guard(role: Role) -> Result<(), String> {
+    if State::get().borrow().has_role(caller(), role) {
+        Ok(())
+    } else {
+        Err(format!("The caller does not have {:?} role", role))
+    }
+}
+
+#[inline(always)]
+pub fn admin_guard() -> Result<(), String> {
+    role_guard(Role::admin)
+}
+
+#[inline(always)]
+pub fn minter_guard() -> Result<(), String> {
+    role_guard(Role::minter)
+}
+
+#[inline(always)]
+pub fn uploader_guard() -> Result<(), String> {
+    role_guard(Role::metadata_uploader)
+}
+
+#[inline(always)]
+pub fn fee_manager_guard() -> Result<(), String> {
+    role_guard(Role::fee_manager)
+}
+
+#[inline(always)]
+pub fn pauser_guard() -> Result<(), String> {
+    role_guard(Role::pauser)
+}
+
 #[inline(always)]
-#[allow(dead_code)]
 pub fn not_paused() -> Result<(), String> {
-    if State::get().borrow().is_paused { return Err(String::from("Canister is paused, try another time")); }
+    State::get().borrow().check_not_paused(PauseSwitch::all)
+}
 
-    Ok(())
+#[inline(always)]
+pub fn transfers_not_paused() -> Result<(), String> {
+    State::get().borrow().check_not_paused(PauseSwitch::transfers)
+}
+
+#[inline(always)]
+pub fn marketplace_not_paused() -> Result<(), String> {
+    State::get().borrow().check_not_paused(PauseSwitch::marketplace)
+}
+
+#[inline(always)]
+pub fn minting_not_paused() -> Result<(), String> {
+    State::get().borrow().check_not_paused(PauseSwitch::minting)
 }
\ No newline at end of file
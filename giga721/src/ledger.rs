@@ -3,7 +3,7 @@ use ic_cdk::export::candid::{CandidType, Deserialize, Principal};
 use ic_cdk_macros::{query, update};
 use std::cell::RefCell;
 use std::rc::Rc;
-use crate::guards::{owner_guard, not_paused};
+use crate::guards::{admin_guard, not_paused};
 
 #[cfg(test)]
 use crate::testing::time;
@@ -27,6 +27,9 @@ pub struct Record {
     pub price: Option<u64>,
     pub timestamp: u64,
     pub memo: u64,
+    /// ICRC-1 token canister the price is denominated in, None for ICP
+    #[serde(default)]
+    pub payment_token: Option<Principal>,
 }
 
 #[derive(Serialize, CandidType, Deserialize, Default)]
@@ -68,6 +71,7 @@ impl Ledger {
             price: None,
             timestamp: time(),
             memo: 0,
+            payment_token: None,
         };
 
         self.add_record(&record);
@@ -86,6 +90,7 @@ impl Ledger {
             price: None,
             timestamp: time(),
             memo: 0,
+            payment_token: None,
         };
 
         self.add_record(&record);
@@ -106,6 +111,116 @@ impl Ledger {
             price: None,
             timestamp: time(),
             memo: 0,
+            payment_token: None,
+        };
+
+        self.add_record(&record);
+
+        record.index
+    }
+
+    //Inserts metadata change to ledger, memo holds new metadata version
+    pub fn update_metadata(&mut self, caller: Principal, token_id: u32, version: u64) -> u64 {
+        let record = Record {
+            index: self.offset + self.tx.len() as u64,
+            caller: caller,
+            op: Operation::update_metadata,
+            from: None,
+            to: None,
+            token_id: token_id,
+            price: None,
+            timestamp: time(),
+            memo: version,
+            payment_token: None,
+        };
+
+        self.add_record(&record);
+
+        record.index
+    }
+
+    //Inserts holder property change to ledger
+    pub fn set_property(&mut self, owner: Principal, token_id: u32) -> u64 {
+        let record = Record {
+            index: self.offset + self.tx.len() as u64,
+            caller: owner,
+            op: Operation::set_property,
+            from: Some(owner),
+            to: None,
+            token_id: token_id,
+            price: None,
+            timestamp: time(),
+            memo: 0,
+            payment_token: None,
+        };
+
+        self.add_record(&record);
+
+        record.index
+    }
+
+    //Inserts recovery transfer of non-transferable token, made by owner of contract
+    pub fn recover(&mut self, caller: Principal, from: Principal, to: Principal, token_id: u32) -> u64 {
+        let record = Record {
+            index: self.offset + self.tx.len() as u64,
+            caller: caller,
+            op: Operation::recover,
+            from: Some(from),
+            to: Some(to),
+            token_id: token_id,
+            price: None,
+            timestamp: time(),
+            memo: 0,
+            payment_token: None,
+        };
+
+        self.add_record(&record);
+
+        record.index
+    }
+
+    //Inserts role grant, memo holds role code
+    pub fn grant_role(&mut self, caller: Principal, to: Principal, role: u64) -> u64 {
+        self.role_record(caller, to, role, Operation::grant_role)
+    }
+
+    //Inserts role revocation, memo holds role code
+    pub fn revoke_role(&mut self, caller: Principal, to: Principal, role: u64) -> u64 {
+        self.role_record(caller, to, role, Operation::revoke_role)
+    }
+
+    //Inserts ownership change, proposal and accept have new owner in to, renounce has none
+    pub fn ownership(&mut self, caller: Principal, from: Option<Principal>, to: Option<Principal>, op: Operation) -> u64 {
+        let record = Record {
+            index: self.offset + self.tx.len() as u64,
+            caller,
+            op,
+            from,
+            to,
+            token_id: 0,
+            price: None,
+            timestamp: time(),
+            memo: 0,
+            payment_token: None,
+        };
+
+        self.add_record(&record);
+
+        record.index
+    }
+
+    fn role_record(&mut self, caller: Principal, to: Principal, role: u64, op: Operation) -> u64 {
+        let record = Record {
+            index: self.offset + self.tx.len() as u64,
+            caller,
+            op,
+            from: Some(caller),
+            to: Some(to),
+            token_id: 0,
+            price: None,
+            timestamp: time(),
+            memo: role,
+            payment_token: None,
         };
 
         self.add_record(&record);
@@ -125,6 +240,7 @@ impl Ledger {
             price: None,
             timestamp: time(),
             memo: 0,
+            payment_token: None,
         };
 
         self.add_record(&record);
@@ -132,7 +248,7 @@ impl Ledger {
         record.index
     }
 
-    pub fn list(&mut self, from: Principal, token_id: u32, price: u64) -> u64 {
+    pub fn list(&mut self, from: Principal, token_id: u32, price: u64, payment_token: Option<Principal>) -> u64 {
         let record = Record {
             index: self.offset + self.tx.len() as u64,
             caller: from,
@@ -143,6 +259,7 @@ impl Ledger {
             price: Some(price),
             timestamp: time(),
             memo: 0,
+            payment_token,
         };
 
         self.add_record(&record);
@@ -161,6 +278,7 @@ impl Ledger {
             price: None,
             timestamp: time(),
             memo: 0,
+            payment_token: None,
         };
 
         self.add_record(&record);
@@ -175,6 +293,7 @@ impl Ledger {
         to: Principal,
         token_id: u32,
         price: u64,
+        payment_token: Option<Principal>,
     ) -> u64 {
         let record = Record {
             index: self.offset + self.tx.len() as u64,
@@ -186,6 +305,7 @@ impl Ledger {
             price: Some(price),
             timestamp: time(),
             memo: 0,
+            payment_token,
         };
 
         self.add_record(&record);
@@ -225,7 +345,7 @@ pub fn tx_amount() -> u128 {
     LEDGER.with(|x| x.borrow().tx.len() as u128)
 }
 
-#[update(guard="owner_guard")]
+#[update(guard="admin_guard")]
 pub fn upload_history(mut data: Vec<Record>) -> bool {
     LEDGER.with(|x| {
         let mut ledger = x.borrow_mut();
@@ -4,9 +4,14 @@ mod ledger;
 
 mod marketplace;
 mod trading;
+mod royalty;
+mod fees;
+mod sale;
+mod random;
 
 
 mod guards;
+mod roles;
 
 mod system;
 mod api;
@@ -1,22 +1,27 @@
 use std::rc::Rc;
-use common::{ SendArgs, TransactionNotification, ICPTs, TransactionResponse };
+use common::{ Operation, Property, TransactionNotification, TransactionResponse, RoyaltyShare, Account, TransferArg, TransferFromArgs };
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 
 #[cfg(test)]
 use crate::testing::{time};
 #[cfg(test)]
-use crate::testing::{call_send_dfx};
+use crate::testing::{call_icrc1_fee, call_icrc1_transfer, call_icrc2_transfer_from, id};
 
 #[cfg(not(test))]
 use ic_cdk::api::time;
 #[cfg(not(test))]
-use common::call_send_dfx;
+use common::{call_icrc1_fee, call_icrc1_transfer, call_icrc2_transfer_from};
+#[cfg(not(test))]
+use ic_cdk::id;
 
-use ic_cdk::export::candid::{CandidType, Deserialize, Principal};
+use ic_cdk::export::candid::{CandidType, Deserialize, Nat, Principal};
 
-use crate::token::STATE;
+use crate::token::{PauseSwitch, STATE};
 use crate::ledger::LEDGER;
+use crate::royalty::{self, Royalty};
+use crate::fees::{self, FeeConfig, FEE_DENOMINATOR};
+use crate::sale::{Sale, MINT_MEMO_FLAG};
 
 use serde::Serialize;
 
@@ -38,18 +43,191 @@ thread_local! {
 
 #[derive(Clone, CandidType, Deserialize, Serialize)]
 pub struct Listing {
+    /// Listing version, changes every time the listing is created or repriced
     pub index: u64,
     pub owner: Principal,
     pub token_id: u32,
     pub price: u64,
 
     pub time: u64,
+
+    /// ICRC-1 token canister the price is denominated in, None for ICP
+    #[serde(default)]
+    pub payment_token: Option<Principal>,
+}
+
+const DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const WEEK: u64 = 7 * DAY;
+
+#[derive(Clone, Copy, CandidType, Deserialize, Serialize, PartialEq, Debug)]
+#[allow(non_camel_case_types)]
+pub enum Period {
+    daily,
+    weekly,
+}
+
+/// Sales aggregated over one day or week, start is the bucket start time in nanoseconds
+#[derive(Serialize, CandidType, Deserialize, Default, Clone, PartialEq, Debug)]
+pub struct VolumeBucket {
+    pub start: u64,
+    pub volume: u64,
+    pub sales: u64,
+    pub low: u64,
+    pub high: u64,
+}
+
+impl VolumeBucket {
+    fn add(&mut self, price: u64) {
+        if self.sales == 0 || price < self.low { self.low = price; }
+        if price > self.high { self.high = price; }
+        self.volume += price;
+        self.sales += 1;
+    }
+}
+
+#[derive(Serialize, CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub struct LastSale {
+    pub price: u64,
+    pub time: u64,
+    pub seller: Principal,
+    pub buyer: Principal,
+}
+
+/// Sale of token, price is in ICP e8s or in units of payment_token when given
+#[derive(Serialize, CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub struct PricePoint {
+    pub time: u64,
+    pub price: u64,
+    pub payment_token: Option<Principal>,
 }
 
+/// Sale statistics, volume, sales counts and buckets count only sales priced in ICP since prices in other tokens cannot be summed with them
 #[derive(Serialize, CandidType, Deserialize, Default, Clone)]
 pub struct Stats {
     pub highest_sell: u64,
     pub volume_traded: u64,
+
+    #[serde(default)]
+    pub sales: u64,
+    /// Volume of the sales counted in sales, volume_traded also holds sales made before sales were counted
+    #[serde(default)]
+    pub sales_volume: u64,
+    #[serde(default)]
+    pub last_sales: HashMap<u32, LastSale>,
+    /// Volume buckets keyed by day and week number since epoch
+    #[serde(default)]
+    pub daily: BTreeMap<u64, VolumeBucket>,
+    #[serde(default)]
+    pub weekly: BTreeMap<u64, VolumeBucket>,
+    #[serde(default)]
+    pub buyers: HashSet<Principal>,
+    #[serde(default)]
+    pub sellers: HashSet<Principal>,
+    /// Sales of every token in ICP and ICRC-1 tokens, oldest first
+    #[serde(default)]
+    pub price_history: HashMap<u32, Vec<PricePoint>>,
+}
+
+impl Stats {
+    fn add_sale(&mut self, token_id: u32, price: u64, seller: Principal, buyer: Principal, time: u64) {
+        self.volume_traded += price;
+        if price > self.highest_sell {
+            self.highest_sell = price;
+        }
+
+        self.count_sale(token_id, price, seller, buyer, time);
+    }
+
+    /// Adds ICP sale to sales counts, buckets and price history, volume_traded and highest_sell are left as they are
+    fn count_sale(&mut self, token_id: u32, price: u64, seller: Principal, buyer: Principal, time: u64) {
+        self.sales += 1;
+        self.sales_volume += price;
+
+        self.last_sales.insert(token_id, LastSale { price, time, seller, buyer });
+
+        self.daily.entry(time / DAY).or_insert_with(|| VolumeBucket { start: time / DAY * DAY, ..Default::default() }).add(price);
+        self.weekly.entry(time / WEEK).or_insert_with(|| VolumeBucket { start: time / WEEK * WEEK, ..Default::default() }).add(price);
+
+        self.buyers.insert(buyer);
+        self.sellers.insert(seller);
+
+        self.add_price(token_id, price, None, time);
+    }
+
+    fn add_price(&mut self, token_id: u32, price: u64, payment_token: Option<Principal>, time: u64) {
+        self.price_history.entry(token_id).or_default().push(PricePoint { time, price, payment_token });
+    }
+
+    /// Fills sales counts, buckets and price history from purchase records of canisters upgraded from versions without them
+    fn rebuild_sales(&mut self) {
+        if !self.price_history.is_empty() { return; }
+
+        self.sales = 0;
+        self.sales_volume = 0;
+        self.last_sales.clear();
+        self.daily.clear();
+        self.weekly.clear();
+        self.buyers.clear();
+        self.sellers.clear();
+
+        LEDGER.with(|x| {
+            for r in x.borrow().tx.iter().filter(|r| matches!(r.op, Operation::purchase)) {
+                match (r.price, r.payment_token, r.from, r.to) {
+                    (Some(price), None, Some(seller), Some(buyer)) => self.count_sale(r.token_id, price, seller, buyer, r.timestamp),
+                    (Some(price), payment_token, _, _) => self.add_price(r.token_id, price, payment_token, r.timestamp),
+                    _ => {},
+                }
+            }
+        });
+    }
+
+    /// Returns average price of counted ICP sales
+    pub fn average_price(&self) -> u64 {
+        self.sales_volume.checked_div(self.sales).unwrap_or(0)
+    }
+
+    /// Returns buckets of given period that start between from and to (nanoseconds)
+    pub fn volume_history(&self, period: Period, from: u64, to: u64) -> Vec<VolumeBucket> {
+        let (buckets, size) = match period {
+            Period::daily => (&self.daily, DAY),
+            Period::weekly => (&self.weekly, WEEK),
+        };
+
+        if from > to { return vec![]; }
+
+        buckets.range(from / size..=to / size).map(|(_, x)| x.clone()).collect()
+    }
+}
+
+const MAX_PAGE_SIZE: u64 = 100;
+
+#[derive(Clone, Copy, CandidType, Deserialize, PartialEq, Debug)]
+#[allow(non_camel_case_types)]
+pub enum ListingSort {
+    price_asc,
+    price_desc,
+    newest,
+    token_id,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct ListingQuery {
+    pub sort: ListingSort,
+    pub cursor: Option<u64>,
+    pub limit: u64,
+    pub min_price: Option<u64>,
+    pub max_price: Option<u64>,
+    /// Listings priced in this ICRC-1 token, None for ICP
+    pub payment_token: Option<Principal>,
+    /// Token metadata filter, properties with the same name are alternatives
+    pub properties: Vec<Property>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct ListingPage {
+    pub listings: Vec<Listing>,
+    pub next_cursor: Option<u64>,
+    pub total: u64,
 }
 
 #[derive(Serialize, CandidType, Deserialize, Default, Clone)]
@@ -57,7 +235,37 @@ pub struct StatsResult {
     pub highest_sell: u64,
     pub volume_traded: u64,
     pub owners: u64,
-    pub listings: u64
+    pub listings: u64,
+
+    pub floor_price: Option<u64>,
+    pub sales: u64,
+    pub average_price: u64,
+    pub unique_buyers: u64,
+    pub unique_sellers: u64,
+}
+
+/// Default time a listing stays locked for reserving buyer, 3 minutes in nanoseconds
+const RESERVATION_WINDOW: u64 = 3 * 60 * 1_000_000_000;
+/// Time after expiry before the same buyer can reserve the token again, in nanoseconds
+const RESERVATION_COOLDOWN: u64 = 10 * 60 * 1_000_000_000;
+/// Maximum number of listings one buyer can hold reserved at once
+const MAX_RESERVATIONS_PER_BUYER: usize = 3;
+
+/// Lock of ICP listing price for one buyer, payment must carry the memo and arrive from the buyer, seller can still reprice, delist or move the token
+#[derive(Clone, CandidType, Deserialize, Serialize, PartialEq, Debug)]
+pub struct Reservation {
+    pub token_id: u32,
+    pub buyer: Principal,
+    pub memo: u64,
+    pub price: u64,
+    pub expires_at: u64,
+}
+
+impl Listing {
+    /// Memo expected in ICP payment for this listing, listing index in high 32 bits and token_id in low 32 bits
+    pub fn memo(&self) -> u64 {
+        (self.index << 32) | self.token_id as u64
+    }
 }
 
 #[derive(Serialize, CandidType, Deserialize, Default)]
@@ -65,6 +273,10 @@ pub struct Marketplace {
     pub creators_fee: u128,
     pub creators_address: Option<Principal>,
 
+    /// Royalty table, when set it replaces creators_fee and creators_address
+    #[serde(default)]
+    pub royalties: Vec<Royalty>,
+
     //Used to verify who is sending notification
     pub notify_canister: Option<Principal>,
 
@@ -76,21 +288,81 @@ pub struct Marketplace {
     pub listing_offset: u64,
     pub listings: HashMap<u32, Listing>,
 
+    /// Listings ordered by (price, token_id), rebuilt from listings after upgrade
+    #[serde(skip)]
+    pub price_index: BTreeSet<(u64, u32)>,
+
     // pub payment_offset: u64,
     // pub payments: Vec<Payment>,
 
     pub stats: Stats,
+
+    /// Market fee configuration, reported to the ledger proxy with every purchase
+    #[serde(default)]
+    pub fees: FeeConfig,
+
+    /// Active listing locks, expired entries are ignored and dropped lazily
+    #[serde(default)]
+    pub reservations: HashMap<u32, Reservation>,
+    /// Reservation lock time in nanoseconds, 0 uses RESERVATION_WINDOW
+    #[serde(default)]
+    pub reservation_window: u64,
+
+    /// Amounts owed from ICRC-1 settlements, payment token -> recipient -> amount
+    #[serde(default)]
+    pub balances: HashMap<Principal, HashMap<Principal, u64>>,
+
+    /// Primary sale, paid in ICP through transaction_notification
+    #[serde(default)]
+    pub sale: Sale,
+
+    /// ICRC-1 token canisters listings can be priced in, managed by admins
+    #[serde(default)]
+    pub payment_tokens: HashSet<Principal>,
 }
 
 #[query]
 pub fn stats() -> StatsResult {
-    let stats = MARKETPLACE.with(|x| { x.borrow().stats.clone() });
-    StatsResult {
-        highest_sell: stats.highest_sell,
-        volume_traded: stats.volume_traded,
-        owners: STATE.with(|x| { x.borrow().owners.len() }) as u64,
-        listings: MARKETPLACE.with(|x| x.borrow().listings.len() ) as u64,
-    }
+    MARKETPLACE.with(|x| {
+        let market = x.borrow();
+        let stats = &market.stats;
+
+        StatsResult {
+            highest_sell: stats.highest_sell,
+            volume_traded: stats.volume_traded,
+            owners: STATE.with(|x| { x.borrow().owners.len() }) as u64,
+            listings: market.listings.len() as u64,
+
+            floor_price: market.floor_price(),
+            sales: stats.sales,
+            average_price: stats.average_price(),
+            unique_buyers: stats.buyers.len() as u64,
+            unique_sellers: stats.sellers.len() as u64,
+        }
+    })
+}
+
+/// Returns lowest price of listings priced in ICP
+#[query]
+pub fn floor_price() -> Option<u64> {
+    MARKETPLACE.with(|x| x.borrow().floor_price())
+}
+
+#[query]
+pub fn last_sale(token_id: u32) -> Option<LastSale> {
+    MARKETPLACE.with(|x| x.borrow().stats.last_sales.get(&token_id).cloned())
+}
+
+/// Returns daily or weekly volume buckets starting between from and to, times are in nanoseconds
+#[query]
+pub fn volume_history(period: Period, from: u64, to: u64) -> Vec<VolumeBucket> {
+    MARKETPLACE.with(|x| x.borrow().stats.volume_history(period, from, to))
+}
+
+/// Returns every sale of given token, oldest first
+#[query]
+pub fn price_history(token_id: u32) -> Vec<PricePoint> {
+    MARKETPLACE.with(|x| x.borrow().stats.price_history.get(&token_id).cloned().unwrap_or_default())
 }
 
 impl Marketplace {
@@ -103,23 +375,121 @@ impl Marketplace {
         Ok(())
     }
 
-    ///Adds token to listing, this will not check if from principal owns the listed token!
-    pub fn list(&mut self, from: Principal, token_id: u32, price: u64) -> Result<u64, String> {
+    /// Removes listings of tokens that became non-transferable
+    pub fn remove_untransferable(&mut self) {
+        let tokens: Vec<(u32, Principal)> = STATE.with(|x| {
+            let state = x.borrow();
+            self.listings.values().filter(|x| state.check_transferable(x.token_id).is_err()).map(|x| (x.token_id, x.owner)).collect()
+        });
+
+        for (token_id, owner) in tokens {
+            self.remove_listing(token_id);
+            self.reservations.remove(&token_id);
+            LEDGER.with(|x| x.borrow_mut().delist(owner, token_id));
+        }
+    }
+
+    /// Inserts or replaces listing, keeps price index in sync
+    fn insert_listing(&mut self, listing: Listing) {
+        self.remove_listing(listing.token_id);
+        self.price_index.insert((listing.price, listing.token_id));
+        self.listings.insert(listing.token_id, listing);
+    }
+
+    /// Removes listing, keeps price index in sync
+    fn remove_listing(&mut self, token_id: u32) -> Option<Listing> {
+        let listing = self.listings.remove(&token_id)?;
+        self.price_index.remove(&(listing.price, token_id));
+        Some(listing)
+    }
+
+    /// Rebuilds price index from listings, index is not kept in stable memory
+    pub fn rebuild_index(&mut self) {
+        self.price_index = self.listings.values().map(|x| (x.price, x.token_id)).collect();
+        self.stats.rebuild_sales();
+    }
+
+    /// Returns page of listings matching query, cursor is the number of already returned items
+    pub fn query_listings(&self, query: &ListingQuery) -> ListingPage {
+        let min = query.min_price.unwrap_or(0);
+        let max = query.max_price.unwrap_or(u64::MAX);
+        if min > max { return ListingPage { listings: vec![], next_cursor: None, total: 0 }; }
+
+        //Filters grouped by property name, token must match one of the values for every name
+        let mut filters: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for p in query.properties.iter() {
+            filters.entry(&p.name).or_default().push(&p.value);
+        }
+
+        let mut matching: Vec<&Listing> = STATE.with(|state| {
+            let state = state.borrow();
+
+            self.price_index
+                .range((min, 0)..=(max, u32::MAX))
+                .filter_map(|(_, token_id)| self.listings.get(token_id))
+                .filter(|x| x.payment_token == query.payment_token)
+                .filter(|x| {
+                    if filters.is_empty() { return true; }
+                    let token = match state.revealed_token(x.token_id) { Some(token) => token, None => return false };
+                    filters.iter().all(|(name, values)| {
+                        token.properties.iter().any(|p| p.name == *name && values.contains(&p.value.as_str()))
+                    })
+                })
+                .collect()
+        });
+
+        //Price index already gives ascending order
+        match query.sort {
+            ListingSort::price_asc => {},
+            ListingSort::price_desc => matching.reverse(),
+            ListingSort::newest => matching.sort_by(|a, b| b.time.cmp(&a.time).then(b.index.cmp(&a.index))),
+            ListingSort::token_id => matching.sort_by_key(|x| x.token_id),
+        }
+
+        let total = matching.len() as u64;
+        let start = query.cursor.unwrap_or(0).min(total);
+        let limit = query.limit.clamp(1, MAX_PAGE_SIZE);
+        let end = (start + limit).min(total);
+
+        ListingPage {
+            listings: matching[start as usize..end as usize].iter().map(|x| (*x).clone()).collect(),
+            next_cursor: if end < total { Some(end) } else { None },
+            total,
+        }
+    }
+
+    ///Adds token to listing, price is in ICP e8s or in units of payment_token when given
+    pub fn list(&mut self, from: Principal, token_id: u32, price: u64, payment_token: Option<Principal>) -> Result<u64, String> {
         self.is_tx_enabled()?;
 
         STATE.with(|x| x.borrow().check_token_id(token_id))?;
 
         //Check if current owner of the token is listing
         let owner = STATE.with(|x| x.borrow().check_owner(token_id, from))?;
+        STATE.with(|x| x.borrow().check_transferable(token_id))?;
 
-        if price < 1000000 { return Err(String::from("Minimum listing price is 0.01")); }
+        //Seller can always reprice, reserved buyer's payment no longer matches the new listing version and is refunded
+        self.reservations.remove(&token_id);
+
+        if let Some(payment_token) = payment_token {
+            if !self.payment_tokens.contains(&payment_token) { return Err(String::from("Payment token is not accepted")); }
+        }
+
+        //Minimum price applies only to ICP, ICRC-1 tokens have their own decimals
+        if payment_token.is_none() && price < 1000000 { return Err(String::from("Minimum listing price is 0.01")); }
+        if price == 0 { return Err(String::from("Listing price must be above 0")); }
 
         //Get or update listing
-        let listing = self.listings.get_mut(&token_id);
+        let listing = self.listings.get(&token_id).cloned();
 
         match listing {
-            Some(listing) => {
+            Some(mut listing) => {
+                //New version of listing, payments made for the previous price are rejected
+                self.listing_offset += 1;
+                listing.index = self.listing_offset;
                 listing.price = price;
+                listing.payment_token = payment_token;
+                self.insert_listing(listing);
             },
             None => {
                 self.listing_offset += 1;
@@ -128,22 +498,26 @@ impl Marketplace {
                     owner: owner,
                     token_id: token_id,
                     price: price,
-                    time: time()
+                    time: time(),
+                    payment_token,
                 };
-                self.listings.insert(token_id, item);
+                self.insert_listing(item);
             } 
         }
 
         //Add listing to ledger
-        let block = LEDGER.with(|x| x.borrow_mut().list(from, token_id, price));
+        let block = LEDGER.with(|x| x.borrow_mut().list(from, token_id, price, payment_token));
 
         return Ok(block);
     }
 
     ///Removes token from listing, this will not check if from principal owns the delisted token!
     pub fn delist(&mut self, from: Principal, token_id: u32) -> Result<u64, String>  {
+        //Seller can always take reserved token off the market, late payment of the buyer no longer matches a listing and is refunded
+        self.reservations.remove(&token_id);
+
         //Remove listing
-        self.listings.remove(&token_id).ok_or_else(|| String::from("Token is not listed"))?;
+        self.remove_listing(token_id).ok_or_else(|| String::from("Token is not listed"))?;
 
         //Add delist to ledger
         let block = LEDGER.with(|x| x.borrow_mut().delist(from, token_id));
@@ -151,7 +525,107 @@ impl Marketplace {
         Ok(block)
     }
 
-    fn get_ledger_canister(&mut self) -> Result<Principal, String> {
+    /// Lists or reprices several tokens priced in ICP, every item is checked on its own and failed items do not stop the batch
+    pub fn list_many(&mut self, from: Principal, items: &[(u32, u64)]) -> Vec<Result<u64, String>> {
+        items.iter().map(|(token_id, price)| {
+            STATE.with(|x| x.borrow().check_not_paused(PauseSwitch::marketplace))?;
+            //Only token owner can list
+            STATE.with(|x| x.borrow().check_owner(*token_id, from))?;
+            self.list(from, *token_id, *price, None)
+        }).collect()
+    }
+
+    /// Removes several tokens from listing, every item is checked on its own and failed items do not stop the batch
+    pub fn delist_many(&mut self, from: Principal, token_ids: &[u32]) -> Vec<Result<u64, String>> {
+        token_ids.iter().map(|token_id| {
+            STATE.with(|x| x.borrow().check_not_paused(PauseSwitch::marketplace))?;
+            //Only token owner can delist
+            STATE.with(|x| x.borrow().check_owner(*token_id, from))?;
+            self.delist(from, *token_id)
+        }).collect()
+    }
+
+    /// Returns reservation of token if it did not expire yet
+    pub fn active_reservation(&self, token_id: u32) -> Option<&Reservation> {
+        self.reservations.get(&token_id).filter(|x| x.expires_at > time())
+    }
+
+    /// Returns error when listing of token is locked by a reservation, drops expired reservation
+    pub fn check_reservation(&mut self, token_id: u32) -> Result<(), String> {
+        if self.active_reservation(token_id).is_some() {
+            return Err(String::from("Listing is reserved by a buyer"));
+        }
+
+        self.reservations.remove(&token_id);
+        Ok(())
+    }
+
+    /// Locks ICP listing for buyer, listing gets new version so only payments with the returned memo are accepted
+    pub fn reserve(&mut self, buyer: Principal, token_id: u32) -> Result<Reservation, String> {
+        self.is_tx_enabled()?;
+
+        let mut listing = self.listings.get(&token_id).ok_or_else(|| String::from("Token is not listed"))?.clone();
+        if listing.payment_token.is_some() { return Err(String::from("Listing is not priced in ICP")); }
+        if listing.owner == buyer { return Err(String::from("Cannot reserve own listing")); }
+
+        if let Some(reservation) = self.active_reservation(token_id) {
+            if reservation.buyer == buyer { return Ok(reservation.clone()); }
+            return Err(String::from("Listing is reserved by another buyer"));
+        }
+
+        //Expired reservation is kept until replaced, so the same buyer cannot renew it right away
+        if self.reservations.get(&token_id).is_some_and(|x| x.buyer == buyer && x.expires_at + RESERVATION_COOLDOWN > time()) {
+            return Err(String::from("Reservation of this listing expired recently, try again later"));
+        }
+
+        let now = time();
+        if self.reservations.values().filter(|x| x.buyer == buyer && x.expires_at > now).count() >= MAX_RESERVATIONS_PER_BUYER {
+            return Err(String::from("Too many active reservations"));
+        }
+
+        self.listing_offset += 1;
+        listing.index = self.listing_offset;
+
+        let window = if self.reservation_window > 0 { self.reservation_window } else { RESERVATION_WINDOW };
+        let reservation = Reservation {
+            token_id,
+            buyer,
+            memo: listing.memo(),
+            price: listing.price,
+            expires_at: now + window,
+        };
+
+        self.insert_listing(listing);
+        self.reservations.insert(token_id, reservation.clone());
+
+        Ok(reservation)
+    }
+
+    /// Returns royalty recipients of token with their fees, per token override goes first, then royalty table and creators_address
+    pub fn royalty_shares(&self, token_id: u32) -> Vec<RoyaltyShare> {
+        if let Some(royalties) = STATE.with(|x| x.borrow().token_royalties.get(&token_id).cloned()) {
+            return royalties.iter().map(|x| x.share()).collect();
+        }
+
+        if !self.royalties.is_empty() {
+            return self.royalties.iter().map(|x| x.share()).collect();
+        }
+
+        match self.creators_address {
+            Some(account) => vec![RoyaltyShare { account, fee: self.creators_fee as u64 }],
+            None => vec![],
+        }
+    }
+
+    /// Returns total creators fee charged on a sale of token, in 1/100000 of price
+    pub fn total_creators_fee(&self, token_id: u32) -> u64 {
+        let has_override = STATE.with(|x| x.borrow().token_royalties.contains_key(&token_id));
+        if !has_override && self.royalties.is_empty() { return self.creators_fee as u64; }
+
+        self.royalty_shares(token_id).iter().map(|x| x.fee).sum()
+    }
+
+    fn get_ledger_canister(&self) -> Result<Principal, String> {
         self.ledger_canister.ok_or_else(|| String::from("Ledger canister not set"))
     }
 
@@ -187,8 +661,19 @@ impl Marketplace {
     // }
 
     //Wrap for purchase, if failed returns funds to original caller 
+    /// Returns error unless caller is the ledger canister allowed to send transaction notifications
+    pub fn check_notifier(&self, caller: Principal) -> Result<(), String> {
+        let ledger_canister = self.get_ledger_canister()?;
+        if caller != ledger_canister { return Err(String::from("Only ledger canister can call notify")); }
+        Ok(())
+    }
+
     pub fn purchase(&mut self, caller: Principal, args: &TransactionNotification)-> Result<TransactionResponse, String> {
-        let result = self._purchase(caller, args);
+        //Sale mints are paid with flagged memo
+        let result = match args.memo & MINT_MEMO_FLAG {
+            0 => self._purchase(caller, args),
+            _ => self.sale_mint(caller, args),
+        };
 
         // match result {
         //     Ok(_) => {}, //Everythin is fine do nothing
@@ -204,36 +689,73 @@ impl Marketplace {
         return result;
     }
 
-    fn update_stats(&mut self, price: u64) {
-        //Update stats
-        self.stats.volume_traded += price;
-        if price > self.stats.highest_sell {
-            self.stats.highest_sell = price;
-        }
+    /// Returns lowest price of listings priced in ICP
+    pub fn floor_price(&self) -> Option<u64> {
+        self.listings.values().filter(|x| x.payment_token.is_none()).map(|x| x.price).min()
+    }
+
+    fn update_stats(&mut self, listing: &Listing, buyer: Principal) {
+        self.stats.add_sale(listing.token_id, listing.price, listing.owner, buyer, time());
+    }
+
+    fn sale_mint(&mut self, caller: Principal, args: &TransactionNotification)-> Result<TransactionResponse, String> {
+        //Check if ledger canister is sending notification
+        self.check_notifier(caller)?;
+
+        let quantity = (args.memo & !MINT_MEMO_FLAG) as u32;
+        let (block, price) = self.sale.mint(args.from, quantity, args.amount.e8s)?;
+
+        //Whole mint price goes to the treasury, no market or creators fee on primary sales
+        let treasury = self.sale.config.treasury.or_else(|| STATE.with(|x| x.borrow().owner))
+            .ok_or_else(|| String::from("Sale treasury not set"))?;
+
+        Ok(TransactionResponse {
+            block,
+            creators_fee: 0,
+            seller: treasury,
+            royalties: Vec::default(),
+            price,
+            market_fee: 0,
+        })
     }
 
     fn _purchase(&mut self, caller: Principal, args: &TransactionNotification)-> Result<TransactionResponse, String> {
         self.is_tx_enabled()?;
-        let ledger_canister = self.get_ledger_canister()?;
         //Check if ledger canister is sending notification
-        if caller != ledger_canister { return Err(String::from("Only ledger canister can call notify"));}
+        self.check_notifier(caller)?;
         let token_id = args.memo as u32;
         //Check if token is listed
         let listing = self.listings.get(&token_id).ok_or_else(|| String::from("Token is not listed"))?.clone();
+        //Listings priced in ICRC-1 tokens are settled with purchase_with_token
+        if listing.payment_token.is_some() { return Err(String::from("Listing is not priced in ICP")); }
+        STATE.with(|x| x.borrow().check_transferable(token_id))?;
+        //Payment must be made for the current version of listing, seller could reprice or relist it in the meantime
+        if listing.memo() != args.memo { return Err(String::from("Listing has changed, payment memo does not match current listing")); }
+        //Reserved listing can be bought only by reserving buyer
+        if let Some(reservation) = self.active_reservation(token_id) {
+            if reservation.buyer != args.from { return Err(String::from("Listing is reserved by another buyer")); }
+        }
         //Check if amount is enough for listing
         if listing.price > args.amount.e8s { return Err(String::from("Sent amount does not satisfy listing price"));}
+        //Listing must still belong to the token owner
+        STATE.with(|x| x.borrow().check_owner(token_id, listing.owner))?;
+
+        let market_fee = self.fees.fee_for(listing.price, fees::holdings(args.from));
+        let creators_fee = self.total_creators_fee(token_id);
+        if market_fee + creators_fee > FEE_DENOMINATOR { return Err(String::from("Fees exceed the sale price")); }
 
         //Remove listed position from listings, it was just purchased
-        self.listings.remove(&token_id);
+        self.remove_listing(token_id);
+        self.reservations.remove(&token_id);
 
         //Move token from seller to buyer
         STATE.with(|x| x.borrow_mut().moved(listing.owner, args.from, token_id));
 
         //Add purchase to ledger
-        let block = LEDGER.with(|x| x.borrow_mut().purchase(caller, listing.owner, args.from, token_id, listing.price));
+        let block = LEDGER.with(|x| x.borrow_mut().purchase(caller, listing.owner, args.from, token_id, listing.price, None));
 
         //Update stats
-        self.update_stats(listing.price);
+        self.update_stats(&listing, args.from);
 
         //Calculate fee and amount to send
         // let mut fee = (listing.price as u128 * self.creators_fee / 100000) as u64;
@@ -256,8 +778,171 @@ impl Marketplace {
             // }
         // }
 
-        return Ok(TransactionResponse { block, creators_fee: self.creators_fee as u64, seller: listing.owner });
+        return Ok(TransactionResponse {
+            block,
+            creators_fee,
+            seller: listing.owner,
+            royalties: self.royalty_shares(token_id),
+            price: listing.price,
+            market_fee,
+        });
+    }
+
+    /// Takes ICRC-1 priced listing off the market while buyer payment is escrowed
+    pub fn begin_token_purchase(&mut self, buyer: Principal, token_id: u32) -> Result<Listing, String> {
+        self.is_tx_enabled()?;
+        let listing = self.listings.get(&token_id).ok_or_else(|| String::from("Token is not listed"))?.clone();
+        if listing.payment_token.is_none() { return Err(String::from("Listing is priced in ICP, pay through the ledger")); }
+        if !listing.payment_token.is_some_and(|x| self.payment_tokens.contains(&x)) { return Err(String::from("Payment token is not accepted")); }
+        if listing.owner == buyer { return Err(String::from("Cannot purchase own listing")); }
+        self.check_reservation(token_id)?;
+        STATE.with(|x| x.borrow().check_transferable(token_id))?;
+
+        self.remove_listing(token_id);
+
+        Ok(listing)
+    }
+
+    /// Puts listing back on the market after failed escrow, listing is dropped if seller moved or relisted the token meanwhile
+    pub fn cancel_token_purchase(&mut self, listing: Listing) {
+        let unchanged = STATE.with(|x| {
+            let state = x.borrow();
+            state.check_owner(listing.token_id, listing.owner).is_ok() && state.check_transferable(listing.token_id).is_ok()
+        });
+
+        if unchanged && !self.listings.contains_key(&listing.token_id) {
+            self.insert_listing(listing);
+            return;
+        }
+
+        //Listing was taken off without a record while escrow was in flight
+        LEDGER.with(|x| x.borrow_mut().delist(listing.owner, listing.token_id));
+    }
+
+    /// Moves token to buyer after payment was escrowed, credits royalties and returns ledger block with amount owed to seller
+    pub fn complete_token_purchase(&mut self, buyer: Principal, listing: &Listing) -> Result<(u64, u64), String> {
+        let payment_token = listing.payment_token.ok_or_else(|| String::from("Listing is priced in ICP"))?;
+        //Seller could have moved the token while payment was in flight
+        STATE.with(|x| x.borrow().check_owner(listing.token_id, listing.owner))?;
+        STATE.with(|x| x.borrow().check_transferable(listing.token_id))?;
+
+        //Market fee is charged only when there is an address to keep it for
+        let market_fee = match self.fees.market_address {
+            Some(_) => self.fees.fee_for(listing.price, fees::holdings(buyer)),
+            None => 0,
+        };
+        if market_fee + self.total_creators_fee(listing.token_id) > FEE_DENOMINATOR { return Err(String::from("Fees exceed the sale price")); }
+
+        STATE.with(|x| x.borrow_mut().moved(listing.owner, buyer, listing.token_id));
+        //Seller could have listed the token again while payment was in flight
+        self.remove_listing(listing.token_id);
+        self.reservations.remove(&listing.token_id);
+
+        let block = LEDGER.with(|x| x.borrow_mut().purchase(buyer, listing.owner, buyer, listing.token_id, listing.price, Some(payment_token)));
+        self.stats.add_price(listing.token_id, listing.price, Some(payment_token), time());
+
+        //Market fee and royalties are kept as recipient balances until withdrawn
+        let mut seller_amount = listing.price;
+        if let Some(market_address) = self.fees.market_address {
+            let amount = (listing.price as u128 * market_fee as u128 / FEE_DENOMINATOR as u128) as u64;
+            self.credit(payment_token, market_address, amount);
+            seller_amount -= amount;
+        }
+        for (account, amount) in royalty::split(listing.price, &self.royalty_shares(listing.token_id)) {
+            self.credit(payment_token, account, amount);
+            seller_amount -= amount;
+        }
+
+        Ok((block, seller_amount))
     }
+
+    /// Adds amount to balance of recipient in given payment token
+    pub fn credit(&mut self, payment_token: Principal, to: Principal, amount: u64) {
+        if amount == 0 { return; }
+        *self.balances.entry(payment_token).or_default().entry(to).or_default() += amount;
+    }
+
+    /// Removes and returns whole balance of owner in given payment token
+    pub fn take_balance(&mut self, payment_token: Principal, owner: Principal) -> u64 {
+        self.balances.get_mut(&payment_token).and_then(|x| x.remove(&owner)).unwrap_or(0)
+    }
+
+    pub fn balance_of(&self, payment_token: Principal, owner: Principal) -> u64 {
+        self.balances.get(&payment_token).and_then(|x| x.get(&owner)).copied().unwrap_or(0)
+    }
+}
+
+/// Sends ICRC-1 tokens from canister account, token ledger fee is deducted from amount
+pub async fn send_token(payment_token: Principal, to: Principal, amount: u64) -> Result<u64, String> {
+    let fee = call_icrc1_fee(payment_token).await?;
+    if amount <= fee { return Err(String::from("Amount does not cover token transfer fee")); }
+
+    let args = TransferArg {
+        from_subaccount: None,
+        to: Account::from(to),
+        amount: Nat::from(amount - fee),
+        fee: Some(Nat::from(fee)),
+        memo: None,
+        created_at_time: None,
+    };
+
+    call_icrc1_transfer(payment_token, &args).await
+}
+
+/// Sends tokens and keeps them as recipient balance if transfer fails, so they can be withdrawn later
+async fn pay_out(payment_token: Principal, to: Principal, amount: u64) {
+    if send_token(payment_token, to, amount).await.is_err() {
+        Marketplace::get().borrow_mut().credit(payment_token, to, amount);
+    }
+}
+
+/// Settles ICRC-1 priced listing with ICRC-2 transfer_from, buyer must first approve this canister for price plus ledger fee
+pub async fn purchase_with_token(buyer: Principal, token_id: u32) -> Result<u64, String> {
+    let listing = Marketplace::get().borrow_mut().begin_token_purchase(buyer, token_id)?;
+    let payment_token = listing.payment_token.ok_or_else(|| String::from("Listing is priced in ICP"))?;
+
+    //Escrow price in canister account
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account::from(buyer),
+        to: Account::from(id()),
+        amount: Nat::from(listing.price),
+        fee: None,
+        memo: Some((token_id as u64).to_be_bytes().to_vec()),
+        created_at_time: None,
+    };
+
+    if let Err(e) = call_icrc2_transfer_from(payment_token, &args).await {
+        Marketplace::get().borrow_mut().cancel_token_purchase(listing);
+        return Err(e);
+    }
+
+    let result = Marketplace::get().borrow_mut().complete_token_purchase(buyer, &listing);
+
+    match result {
+        Ok((block, seller_amount)) => {
+            pay_out(payment_token, listing.owner, seller_amount).await;
+            Ok(block)
+        }
+        Err(e) => {
+            //Return escrowed payment to buyer
+            pay_out(payment_token, buyer, listing.price).await;
+            Err(e)
+        }
+    }
+}
+
+/// Sends whole balance of owner in given payment token
+pub async fn withdraw(owner: Principal, payment_token: Principal) -> Result<u64, String> {
+    let amount = Marketplace::get().borrow_mut().take_balance(payment_token, owner);
+    if amount == 0 { return Err(String::from("Nothing to withdraw")); }
+
+    let result = send_token(payment_token, owner, amount).await;
+    if result.is_err() {
+        Marketplace::get().borrow_mut().credit(payment_token, owner, amount);
+    }
+
+    result
 }
 
 #[cfg(test)] 
@@ -265,6 +950,8 @@ mod test {
 use super::*;
 
 use crate::testing::*;
+use crate::token::Token;
+use common::ICPTs;
 
     #[test]
     fn test_list() {
@@ -277,7 +964,7 @@ use crate::testing::*;
 
         Marketplace::get().borrow_mut().tx_enabled = true;
 
-        let list = Marketplace::get().borrow_mut().list(user_a(), 1, 100000000);
+        let list = Marketplace::get().borrow_mut().list(user_a(), 1, 100000000, None);
 
         assert_eq!(list, Ok(1));
     }
@@ -293,13 +980,53 @@ use crate::testing::*;
 
         Marketplace::get().borrow_mut().tx_enabled = true;
 
-        let list = Marketplace::get().borrow_mut().list(user_a(), 1, 100000000);
+        let list = Marketplace::get().borrow_mut().list(user_a(), 1, 100000000, None);
         assert_eq!(list, Ok(1));
 
         let list = Marketplace::get().borrow_mut().delist(user_a(), 1);
         assert_eq!(list, Ok(2));
     }
 
+    #[test]
+    fn test_list_many_delist_many() {
+        set_state();
+        set_marketplace();
+
+        for token_id in 1..=3 {
+            assert!(STATE.with(|x| x.borrow_mut().mint_token_id(user_a(), user_a(), token_id)).is_ok());
+        }
+        assert!(STATE.with(|x| x.borrow_mut().mint_token_id(user_b(), user_b(), 4)).is_ok());
+
+        //Failed items are reported without stopping the batch
+        let list = Marketplace::get().borrow_mut().list_many(user_a(), &[(1, 100000000), (4, 100000000), (2, 100), (3, 200000000)]);
+        assert_eq!(list.len(), 4);
+        assert!(list[0].is_ok());
+        assert_eq!(list[1], Err(String::from("This token does not belong to caller")));
+        assert_eq!(list[2], Err(String::from("Minimum listing price is 0.01")));
+        assert!(list[3].is_ok());
+
+        let listed: Vec<u32> = Marketplace::get().borrow().listings.keys().copied().collect();
+        assert_eq!(listed.len(), 2);
+        assert!(listed.contains(&1) && listed.contains(&3));
+
+        //Pause is checked for every item
+        STATE.with(|x| x.borrow_mut().marketplace_paused = true);
+        let delist = Marketplace::get().borrow_mut().delist_many(user_a(), &[1, 3]);
+        assert!(delist.iter().all(|x| x.as_ref().is_err_and(|e| e.starts_with("Canister is paused"))));
+        assert_eq!(Marketplace::get().borrow().listings.len(), 2);
+
+        STATE.with(|x| x.borrow_mut().marketplace_paused = false);
+        let delist = Marketplace::get().borrow_mut().delist_many(user_b(), &[1, 4]);
+        assert_eq!(delist[0], Err(String::from("This token does not belong to caller")));
+        assert_eq!(delist[1], Err(String::from("Token is not listed")));
+
+        let delist = Marketplace::get().borrow_mut().delist_many(user_a(), &[1, 2, 3]);
+        assert!(delist[0].is_ok());
+        assert_eq!(delist[1], Err(String::from("Token is not listed")));
+        assert!(delist[2].is_ok());
+        assert_eq!(Marketplace::get().borrow().listings.len(), 0);
+    }
+
 
     #[tokio::test]
     async fn test_purchase() {
@@ -313,7 +1040,7 @@ use crate::testing::*;
 
         Marketplace::get().borrow_mut().tx_enabled = true;
 
-        let list = Marketplace::get().borrow_mut().list(user_a(), 1, 1000000);
+        let list = Marketplace::get().borrow_mut().list(user_a(), 1, 1000000, None);
         assert_eq!(list, Ok(1));
 
         let args = TransactionNotification {
@@ -335,4 +1062,351 @@ use crate::testing::*;
 
         // assert_eq!(payments.len(), 2);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_purchase_with_token() {
+        set_state();
+        set_marketplace();
+
+        let owner = user_a();
+        let mint_result = STATE.with(|x| x.borrow_mut().mint_token_id(owner, owner, 1));
+        assert_eq!(mint_result, Ok(0));
+
+        let list = Marketplace::get().borrow_mut().list(user_a(), 1, 100000, Some(ledger()));
+        assert_eq!(list, Ok(1));
+
+        let purchase = purchase_with_token(user_b(), 1).await;
+        assert_eq!(purchase, Ok(2));
+
+        assert_eq!(STATE.with(|x| x.borrow().check_owner(1, user_b())), Ok(user_b()));
+        assert_eq!(Marketplace::get().borrow().listings.len(), 0);
+        assert_eq!(Marketplace::get().borrow().balance_of(ledger(), user_a()), 2500);
+        assert_eq!(price_history(1), vec![PricePoint { time: 0, price: 100000, payment_token: Some(ledger()) }]);
+        assert_eq!(Marketplace::get().borrow().stats.sales, 0);
+    }
+
+    #[test]
+    fn test_relist_during_token_purchase() {
+        set_state();
+        set_marketplace();
+
+        let owner = user_a();
+        STATE.with(|x| x.borrow_mut().mint_token_id(owner, owner, 1)).unwrap();
+        Marketplace::get().borrow_mut().list(owner, 1, 100000, Some(ledger())).unwrap();
+
+        //Seller lists the token again while buyer payment is escrowed
+        let listing = Marketplace::get().borrow_mut().begin_token_purchase(user_b(), 1).unwrap();
+        Marketplace::get().borrow_mut().list(owner, 1, 100000000, None).unwrap();
+        assert!(Marketplace::get().borrow_mut().complete_token_purchase(user_b(), &listing).is_ok());
+        assert_eq!(Marketplace::get().borrow().listings.len(), 0);
+
+        //Listing of previous owner cannot be bought
+        Marketplace::get().borrow_mut().list(user_b(), 1, 100000000, None).unwrap();
+        STATE.with(|x| x.borrow_mut().moved(user_b(), owner, 1));
+        let args = TransactionNotification {
+            amount: ICPTs { e8s: 100000000 },
+            block_height: 12345,
+            from: ledger(),
+            from_subaccount: None,
+            memo: Marketplace::get().borrow().listings[&1].memo(),
+            to: ledger(),
+            to_subaccount: None
+        };
+        let purchase = Marketplace::get().borrow_mut().purchase(ledger(), &args).map(|x| x.block);
+        assert_eq!(purchase, Err(String::from("This token does not belong to caller")));
+        assert_eq!(STATE.with(|x| x.borrow().check_owner(1, owner)), Ok(owner));
+    }
+
+    #[test]
+    fn test_payment_token_allowlist() {
+        set_state();
+        set_marketplace();
+
+        let owner = user_a();
+        STATE.with(|x| x.borrow_mut().mint_token_id(owner, owner, 1)).unwrap();
+
+        let list = Marketplace::get().borrow_mut().list(user_a(), 1, 100000, Some(user_b()));
+        assert_eq!(list, Err(String::from("Payment token is not accepted")));
+
+        Marketplace::get().borrow_mut().list(user_a(), 1, 100000, Some(ledger())).unwrap();
+        Marketplace::get().borrow_mut().payment_tokens.clear();
+        let purchase = Marketplace::get().borrow_mut().begin_token_purchase(user_b(), 1).map(|x| x.token_id);
+        assert_eq!(purchase, Err(String::from("Payment token is not accepted")));
+    }
+
+    #[test]
+    fn test_cancel_token_purchase() {
+        set_state();
+        set_marketplace();
+
+        let owner = user_a();
+        STATE.with(|x| x.borrow_mut().mint_token_id(owner, owner, 1)).unwrap();
+        Marketplace::get().borrow_mut().list(user_a(), 1, 100000, Some(ledger())).unwrap();
+
+        //Failed escrow puts unchanged listing back
+        let listing = Marketplace::get().borrow_mut().begin_token_purchase(user_b(), 1).unwrap();
+        Marketplace::get().borrow_mut().cancel_token_purchase(listing);
+        assert_eq!(Marketplace::get().borrow().listings.len(), 1);
+
+        //Token moved by seller while escrow was in flight is not listed again
+        let listing = Marketplace::get().borrow_mut().begin_token_purchase(user_b(), 1).unwrap();
+        STATE.with(|x| x.borrow_mut().moved(user_a(), user_b(), 1));
+        Marketplace::get().borrow_mut().cancel_token_purchase(listing);
+        assert_eq!(Marketplace::get().borrow().listings.len(), 0);
+    }
+
+    #[test]
+    fn test_stats() {
+        set_state();
+        set_marketplace();
+
+        let owner = user_a();
+        let _ = STATE.with(|x| x.borrow_mut().mint_token_id(owner, owner, 1));
+        let _ = STATE.with(|x| x.borrow_mut().mint_token_id(owner, owner, 2));
+
+        let _ = Marketplace::get().borrow_mut().list(owner, 1, 200000000, None);
+        let _ = Marketplace::get().borrow_mut().list(owner, 2, 300000000, None);
+        assert_eq!(floor_price(), Some(200000000));
+
+        let args = TransactionNotification {
+            amount: ICPTs { e8s: 200000000 },
+            block_height: 12345,
+            from: user_b(),
+            from_subaccount: None,
+            memo: Marketplace::get().borrow().listings[&1].memo(),
+            to: ledger(),
+            to_subaccount: None
+        };
+        assert!(Marketplace::get().borrow_mut().purchase(ledger(), &args).is_ok());
+
+        let result = stats();
+        assert_eq!(result.sales, 1);
+        assert_eq!(result.floor_price, Some(300000000));
+        assert_eq!(result.average_price, 200000000);
+        assert_eq!(result.unique_buyers, 1);
+
+        assert_eq!(last_sale(1).map(|x| x.buyer), Some(user_b()));
+        assert_eq!(volume_history(Period::daily, 0, 0).len(), 1);
+        assert_eq!(price_history(1), vec![PricePoint { time: 0, price: 200000000, payment_token: None }]);
+    }
+
+    #[test]
+    fn test_rebuild_sales() {
+        set_state();
+        set_marketplace();
+
+        LEDGER.with(|x| {
+            let mut tx = x.borrow_mut();
+            tx.purchase(ledger(), user_a(), user_b(), 1, 100000000, None);
+            tx.purchase(ledger(), user_b(), user_a(), 1, 300000000, None);
+            tx.purchase(user_b(), user_a(), user_b(), 2, 5000, Some(ledger()));
+        });
+
+        //Upgraded canister keeps volume of sales made before sales were counted
+        Marketplace::get().borrow_mut().stats.volume_traded = 900000000;
+        Marketplace::get().borrow_mut().rebuild_index();
+
+        let result = stats();
+        assert_eq!(result.volume_traded, 900000000);
+        assert_eq!(result.sales, 2);
+        assert_eq!(result.average_price, 200000000);
+        assert_eq!(result.unique_buyers, 2);
+        assert_eq!(last_sale(1).map(|x| x.buyer), Some(user_a()));
+        assert_eq!(volume_history(Period::daily, 0, 0).first().map(|x| x.volume), Some(400000000));
+        assert_eq!(price_history(1).len(), 2);
+        assert_eq!(price_history(2), vec![PricePoint { time: 0, price: 5000, payment_token: Some(ledger()) }]);
+
+        //Rebuild runs only once
+        Marketplace::get().borrow_mut().rebuild_index();
+        assert_eq!(stats().sales, 2);
+    }
+
+    #[test]
+    fn test_query_listings() {
+        set_state();
+        set_marketplace();
+
+        let owner = user_a();
+        for id in 1..=4 {
+            let _ = STATE.with(|x| x.borrow_mut().mint_token_id(owner, owner, id));
+            let _ = Marketplace::get().borrow_mut().list(owner, id, 100000000 * (5 - id) as u64, None);
+        }
+
+        STATE.with(|x| x.borrow_mut().store_tokens(&vec![Token {
+            id: 2,
+            url: String::from("url"),
+            name: String::from("Name"),
+            desc: String::from("Desc"),
+            properties: vec![Property { name: String::from("color"), value: String::from("red") }],
+        }])).unwrap();
+
+        let mut query = ListingQuery {
+            sort: ListingSort::price_asc,
+            cursor: None,
+            limit: 2,
+            min_price: None,
+            max_price: Some(300000000),
+            payment_token: None,
+            properties: vec![],
+        };
+
+        let page = Marketplace::get().borrow().query_listings(&query);
+        assert_eq!(page.total, 3);
+        assert_eq!(page.listings.iter().map(|x| x.token_id).collect::<Vec<u32>>(), vec![4, 3]);
+        assert_eq!(page.next_cursor, Some(2));
+
+        query.cursor = page.next_cursor;
+        let page = Marketplace::get().borrow().query_listings(&query);
+        assert_eq!(page.listings.iter().map(|x| x.token_id).collect::<Vec<u32>>(), vec![2]);
+        assert_eq!(page.next_cursor, None);
+
+        query.cursor = None;
+        query.properties = vec![Property { name: String::from("color"), value: String::from("red") }];
+        let page = Marketplace::get().borrow().query_listings(&query);
+        assert_eq!(page.listings.iter().map(|x| x.token_id).collect::<Vec<u32>>(), vec![2]);
+
+        //Repricing moves listing in the index
+        let _ = Marketplace::get().borrow_mut().list(owner, 1, 1000000, None);
+        query.properties = vec![];
+        query.sort = ListingSort::price_desc;
+        let page = Marketplace::get().borrow().query_listings(&query);
+        assert_eq!(page.listings.iter().map(|x| x.token_id).collect::<Vec<u32>>(), vec![2, 3]);
+        assert_eq!(page.total, 4);
+    }
+
+    #[test]
+    fn test_purchase_listing_version() {
+        set_state();
+        set_marketplace();
+
+        let owner = user_a();
+        let _ = STATE.with(|x| x.borrow_mut().mint_token_id(owner, owner, 1));
+        let _ = Marketplace::get().borrow_mut().list(owner, 1, 100000000, None);
+
+        let mut args = TransactionNotification {
+            amount: ICPTs { e8s: 150000000 },
+            block_height: 12345,
+            from: user_b(),
+            from_subaccount: None,
+            memo: Marketplace::get().borrow().listings[&1].memo(),
+            to: ledger(),
+            to_subaccount: None
+        };
+
+        //Seller raises the price before notification arrives
+        let _ = Marketplace::get().borrow_mut().list(owner, 1, 150000000, None);
+        assert!(Marketplace::get().borrow_mut().purchase(ledger(), &args).is_err());
+
+        //Token memo without listing version is rejected as well
+        args.memo = 1;
+        assert!(Marketplace::get().borrow_mut().purchase(ledger(), &args).is_err());
+
+        let _ = Marketplace::get().borrow_mut().list(owner, 1, 100000000, None);
+        args.memo = Marketplace::get().borrow().listings[&1].memo();
+        let response = Marketplace::get().borrow_mut().purchase(ledger(), &args);
+        assert_eq!(response.map(|x| x.price), Ok(100000000));
+    }
+
+    #[test]
+    fn test_reservation() {
+        set_state();
+        set_marketplace();
+
+        let owner = user_a();
+        let _ = STATE.with(|x| x.borrow_mut().mint_token_id(owner, owner, 1));
+        let _ = Marketplace::get().borrow_mut().list(owner, 1, 100000000, None);
+
+        let reservation = Marketplace::get().borrow_mut().reserve(user_b(), 1).unwrap();
+        assert_eq!(reservation.memo, Marketplace::get().borrow().listings[&1].memo());
+
+        //Other buyers cannot reserve it
+        assert!(Marketplace::get().borrow_mut().reserve(ledger(), 1).is_err());
+
+        let mut args = TransactionNotification {
+            amount: ICPTs { e8s: 100000000 },
+            block_height: 12345,
+            from: ledger(),
+            from_subaccount: None,
+            memo: reservation.memo,
+            to: ledger(),
+            to_subaccount: None
+        };
+        assert!(Marketplace::get().borrow_mut().purchase(ledger(), &args).is_err());
+
+        args.from = user_b();
+        assert!(Marketplace::get().borrow_mut().purchase(ledger(), &args).is_ok());
+        assert!(Marketplace::get().borrow().reservations.is_empty());
+    }
+
+    #[test]
+    fn test_reservation_expiry() {
+        set_state();
+        set_marketplace();
+
+        let owner = user_a();
+        let _ = STATE.with(|x| x.borrow_mut().mint_token_id(owner, owner, 1));
+        let _ = Marketplace::get().borrow_mut().list(owner, 1, 100000000, None);
+        let _ = Marketplace::get().borrow_mut().reserve(user_b(), 1).unwrap();
+
+        //Expired reservation no longer locks the listing, but the same buyer has to wait
+        Marketplace::get().borrow_mut().reservations.get_mut(&1).unwrap().expires_at = 0;
+        assert_eq!(Marketplace::get().borrow_mut().reserve(user_b(), 1).map(|x| x.token_id), Err(String::from("Reservation of this listing expired recently, try again later")));
+        assert!(Marketplace::get().borrow_mut().reserve(ledger(), 1).is_ok());
+    }
+
+    #[test]
+    fn test_reservation_limit() {
+        set_state();
+        set_marketplace();
+
+        let owner = user_a();
+        for token_id in 1..=4 {
+            let _ = STATE.with(|x| x.borrow_mut().mint_token_id(owner, owner, token_id));
+            let _ = Marketplace::get().borrow_mut().list(owner, token_id, 100000000, None);
+        }
+
+        for token_id in 1..=3 {
+            assert!(Marketplace::get().borrow_mut().reserve(user_b(), token_id).is_ok());
+        }
+        assert_eq!(Marketplace::get().borrow_mut().reserve(user_b(), 4).map(|x| x.token_id), Err(String::from("Too many active reservations")));
+    }
+
+    #[test]
+    fn test_reservation_seller_exit() {
+        set_state();
+        set_marketplace();
+
+        let owner = user_a();
+        let _ = STATE.with(|x| x.borrow_mut().mint_token_id(owner, owner, 1));
+        let _ = Marketplace::get().borrow_mut().list(owner, 1, 100000000, None);
+        let _ = Marketplace::get().borrow_mut().reserve(user_b(), 1).unwrap();
+
+        //Seller can reprice reserved token, reservation is dropped
+        assert!(Marketplace::get().borrow_mut().list(owner, 1, 200000000, None).is_ok());
+        assert!(Marketplace::get().borrow().active_reservation(1).is_none());
+        let _ = Marketplace::get().borrow_mut().list(owner, 1, 100000000, None);
+        let reservation = Marketplace::get().borrow_mut().reserve(user_b(), 1).unwrap();
+
+        //Seller can delist reserved token, payment made afterwards is rejected and refunded by the proxy
+        assert!(Marketplace::get().borrow_mut().delist(owner, 1).is_ok());
+        assert!(Marketplace::get().borrow().active_reservation(1).is_none());
+
+        let args = TransactionNotification {
+            amount: ICPTs { e8s: 100000000 },
+            block_height: 12345,
+            from: user_b(),
+            from_subaccount: None,
+            memo: reservation.memo,
+            to: ledger(),
+            to_subaccount: None
+        };
+        assert!(Marketplace::get().borrow_mut().purchase(ledger(), &args).is_err());
+
+        //Seller can move reserved token
+        let _ = Marketplace::get().borrow_mut().list(owner, 1, 100000000, None);
+        let _ = Marketplace::get().borrow_mut().reserve(user_b(), 1).unwrap();
+        assert!(STATE.with(|x| x.borrow_mut().transfer(owner, ledger(), 1)).is_ok());
+        assert!(Marketplace::get().borrow().active_reservation(1).is_none());
+        assert_eq!(Marketplace::get().borrow().listings.len(), 0);
+    }
+}
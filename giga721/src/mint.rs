@@ -1,20 +1,113 @@
-use crate::token::{Token, TokenOwner};
+use crate::token::{PauseSwitch, Token, TokenOwner};
 use crate::storage::STORAGE;
 use crate::token::STATE;
 use crate::storage::Asset;
 
 use ic_cdk::{caller};
-use ic_cdk_macros::{update};
-use ic_cdk::export::candid::{Principal};
+use common::Property;
+use ic_cdk_macros::{query, update};
+use ic_cdk::export::candid::{CandidType, Deserialize, Principal};
 
-use crate::guards::{owner_guard};
+use crate::guards::{admin_guard, minter_guard, uploader_guard, minting_not_paused, not_paused};
 
-#[update(guard="owner_guard")]
+#[update(guard="minter_guard")]
 fn mint_for(token_id: u128, owner: Principal) -> Result<u64, String> {
-    STATE.with(|x| x.borrow_mut().mint_token_id(caller(), owner, token_id as u32))
+    STATE.with(|x| {
+        let mut state = x.borrow_mut();
+        state.check_not_paused(PauseSwitch::minting)?;
+        state.mint_token_id(caller(), owner, token_id as u32)
+    })
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct MintMode {
+    pub fair_mint: bool,
+    pub metadata_hidden: bool,
+}
+
+#[query]
+fn mint_mode() -> MintMode {
+    STATE.with(|x| {
+        let state = x.borrow();
+        MintMode { fair_mint: state.fair_mint, metadata_hidden: state.metadata_hidden }
+    })
+}
+
+//Enables random token id draws for sale mints, hide_metadata keeps metadata hidden until reveal
+#[update(guard="minter_guard")]
+fn set_fair_mint(enabled: bool, hide_metadata: bool) -> Result<(), String> {
+    STATE.with(|x| x.borrow_mut().set_fair_mint(enabled, hide_metadata))
+}
+
+//Makes hidden token metadata public
+#[update(guard="uploader_guard")]
+fn reveal() -> Result<(), String> {
+    STATE.with(|x| x.borrow_mut().reveal())
+}
+
+//Commits hex sha256 of token metadata, see metadata_hash for the hashed format
+#[update(guard="uploader_guard")]
+fn set_provenance(hash: String) -> Result<(), String> {
+    STATE.with(|x| x.borrow_mut().set_provenance(hash))
+}
+
+#[query]
+fn provenance() -> Option<String> {
+    STATE.with(|x| x.borrow().provenance.clone())
+}
+
+//Replaces metadata of single token and records the change in ledger, owner or metadata updaters only
+#[update(guard="not_paused")]
+fn update_metadata(token: Token) -> Result<u64, String> {
+    STATE.with(|x| x.borrow_mut().update_metadata(caller(), token))
+}
+
+#[query]
+fn metadata_version(token_id: u32) -> u64 {
+    STATE.with(|x| x.borrow().metadata_versions.get(&token_id).copied().unwrap_or(0))
 }
 
-#[update]
+#[query]
+fn metadata_updaters() -> Vec<Principal> {
+    STATE.with(|x| x.borrow().metadata_updaters.iter().copied().collect())
+}
+
+#[update(guard="admin_guard")]
+fn add_metadata_updater(updater: Principal) -> bool {
+    STATE.with(|x| x.borrow_mut().metadata_updaters.insert(updater))
+}
+
+#[update(guard="admin_guard")]
+fn remove_metadata_updater(updater: Principal) -> bool {
+    STATE.with(|x| x.borrow_mut().metadata_updaters.remove(&updater))
+}
+
+//Sets whitelisted property of caller's token, empty value removes it
+#[update(guard="not_paused")]
+fn set_token_property(token_id: u32, property: Property) -> Result<u64, String> {
+    STATE.with(|x| x.borrow_mut().set_holder_property(caller(), token_id, property))
+}
+
+//Property names token holders may set, with maximum value length
+#[query]
+fn editable_properties() -> Vec<(String, u32)> {
+    STATE.with(|x| x.borrow().editable_properties.iter().map(|(k, v)| (k.clone(), *v)).collect())
+}
+
+#[update(guard="uploader_guard")]
+fn set_editable_properties(properties: Vec<(String, u32)>) -> Result<(), String> {
+    STATE.with(|x| x.borrow_mut().editable_properties = properties.into_iter().collect());
+    Ok(())
+}
+
+//Metadata shown while metadata is hidden, token id is appended to name
+#[update(guard="uploader_guard")]
+fn set_placeholder(placeholder: Token) -> Result<(), String> {
+    STATE.with(|x| x.borrow_mut().placeholder = Some(placeholder));
+    Ok(())
+}
+
+#[update(guard="minting_not_paused")]
 fn burn(token_id: u128) -> Result<u64, String> {
     STATE.with(|x| x.borrow_mut().burn(caller(), token_id as u32))
 }
@@ -39,7 +132,7 @@ fn burn(token_id: u128) -> Result<u64, String> {
 //     return result;
 // }
 
-#[update(guard="owner_guard")]
+#[update(guard="uploader_guard")]
 fn upload_asset(data: Asset) -> Result<(), String> {
     STORAGE.with(|x| {
         x.borrow_mut().store_asset(&data)
@@ -47,11 +140,7 @@ fn upload_asset(data: Asset) -> Result<(), String> {
 }
 
 //Uploads metadata of given token
-#[update(guard="owner_guard")]
+#[update(guard="uploader_guard")]
 fn upload_tokens_metadata(_data: Vec<Token>) -> Result<(), String> {
-    STATE.with(|x| {
-        x.borrow_mut().store_tokens(&_data);
-    });
-
-    Ok(())
+    STATE.with(|x| x.borrow_mut().store_tokens(&_data))
 }
\ No newline at end of file
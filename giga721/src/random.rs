@@ -0,0 +1,25 @@
+#[cfg(test)]
+use crate::testing::{call_raw_rand};
+
+use crate::token::STATE;
+
+/// Mixes fresh management canister randomness into the fair mint seed
+pub async fn refresh_mint_seed() -> Result<(), String> {
+    let bytes = call_raw_rand().await?;
+    STATE.with(|x| x.borrow_mut().mix_mint_seed(&bytes));
+
+    Ok(())
+}
+
+/// Returns 32 random bytes from the management canister
+#[cfg(not(test))]
+async fn call_raw_rand() -> Result<Vec<u8>, String> {
+    use ic_cdk::api::call::call;
+    use ic_cdk::export::candid::Principal;
+
+    let (bytes,): (Vec<u8>,) = call(Principal::management_canister(), "raw_rand", ())
+        .await
+        .map_err(|(_, s)| format!("Error invoking raw_rand, {}", &s))?;
+
+    Ok(bytes)
+}
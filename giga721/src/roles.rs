@@ -0,0 +1,209 @@
+#[cfg(test)]
+use crate::testing::{time};
+#[cfg(not(test))]
+use ic_cdk::api::time;
+
+use ic_cdk::caller;
+use common::Operation;
+use ic_cdk::export::candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::{query, update};
+use serde::Serialize;
+
+use crate::token::{State, STATE};
+use crate::ledger::LEDGER;
+use crate::guards::{owner_guard, admin_guard};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, CandidType, Deserialize, Serialize)]
+#[allow(non_camel_case_types)]
+pub enum Role {
+    admin,
+    minter,
+    metadata_uploader,
+    fee_manager,
+    pauser,
+}
+
+/// How long proposed owner has to accept ownership, 7 days in nanoseconds
+pub const OWNERSHIP_PROPOSAL_WINDOW: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
+impl Role {
+    /// Stored as memo of role ledger records
+    pub fn code(&self) -> u64 {
+        match self {
+            Role::admin => 0,
+            Role::minter => 1,
+            Role::metadata_uploader => 2,
+            Role::fee_manager => 3,
+            Role::pauser => 4,
+        }
+    }
+}
+
+impl State {
+    /// Checks if principal can act in role, owner of contract and admins hold every role
+    pub fn has_role(&self, principal: Principal, role: Role) -> bool {
+        if self.owner == Some(principal) { return true; }
+
+        self.roles.get(&principal).is_some_and(|x| x.contains(&role) || x.contains(&Role::admin))
+    }
+
+    /// Grants role, only owner of contract can grant admin. Returns ledger block
+    pub fn grant_role(&mut self, caller: Principal, principal: Principal, role: Role) -> Result<u64, String> {
+        if role == Role::admin && self.owner != Some(caller) { return Err(String::from("Only owner can grant admin role")); }
+        if !self.roles.entry(principal).or_default().insert(role) { return Err(String::from("Principal already has this role")); }
+
+        Ok(LEDGER.with(|x| x.borrow_mut().grant_role(caller, principal, role.code())))
+    }
+
+    /// Revokes role, only owner of contract can revoke admin. Returns ledger block
+    pub fn revoke_role(&mut self, caller: Principal, principal: Principal, role: Role) -> Result<u64, String> {
+        if role == Role::admin && self.owner != Some(caller) { return Err(String::from("Only owner can revoke admin role")); }
+
+        let roles = self.roles.get_mut(&principal).ok_or_else(|| String::from("Principal does not have this role"))?;
+        if !roles.remove(&role) { return Err(String::from("Principal does not have this role")); }
+        if roles.is_empty() { self.roles.remove(&principal); }
+
+        Ok(LEDGER.with(|x| x.borrow_mut().revoke_role(caller, principal, role.code())))
+    }
+
+    /// Proposes new owner, replaces previous proposal. Returns ledger block
+    pub fn propose_owner(&mut self, caller: Principal, owner: Principal) -> Result<u64, String> {
+        if self.owner != Some(caller) { return Err(String::from("The caller is not the owner of contract")); }
+        if owner == caller { return Err(String::from("Principal is already the owner")); }
+
+        self.pending_owner = Some((owner, time() + OWNERSHIP_PROPOSAL_WINDOW));
+
+        Ok(LEDGER.with(|x| x.borrow_mut().ownership(caller, Some(caller), Some(owner), Operation::propose_owner)))
+    }
+
+    /// Moves ownership to proposed owner calling before proposal expires. Returns ledger block
+    pub fn accept_ownership(&mut self, caller: Principal) -> Result<u64, String> {
+        let (owner, expires_at) = self.pending_owner.ok_or_else(|| String::from("No pending ownership proposal"))?;
+        if owner != caller { return Err(String::from("The caller is not the proposed owner")); }
+        if expires_at <= time() { return Err(String::from("Ownership proposal expired")); }
+
+        let previous = self.owner;
+        self.owner = Some(owner);
+        self.pending_owner = None;
+
+        Ok(LEDGER.with(|x| x.borrow_mut().ownership(caller, previous, Some(owner), Operation::accept_ownership)))
+    }
+
+    /// Leaves contract without owner and admins, so granted roles cannot change anymore. Other roles stay. Returns ledger block
+    pub fn renounce_ownership(&mut self, caller: Principal) -> Result<u64, String> {
+        if self.owner != Some(caller) { return Err(String::from("The caller is not the owner of contract")); }
+
+        //Admins hold every role, they would keep full control of contract without owner
+        let admins: Vec<Principal> = self.roles.iter().filter(|(_, roles)| roles.contains(&Role::admin)).map(|(principal, _)| *principal).collect();
+        for admin in admins {
+            self.revoke_role(caller, admin, Role::admin)?;
+        }
+
+        self.owner = None;
+        self.pending_owner = None;
+
+        Ok(LEDGER.with(|x| x.borrow_mut().ownership(caller, Some(caller), None, Operation::renounce_ownership)))
+    }
+}
+
+#[update(guard="admin_guard")]
+fn grant_role(principal: Principal, role: Role) -> Result<u64, String> {
+    STATE.with(|x| x.borrow_mut().grant_role(caller(), principal, role))
+}
+
+#[update(guard="admin_guard")]
+fn revoke_role(principal: Principal, role: Role) -> Result<u64, String> {
+    STATE.with(|x| x.borrow_mut().revoke_role(caller(), principal, role))
+}
+
+#[update(guard="owner_guard")]
+fn propose_owner(owner: Principal) -> Result<u64, String> {
+    STATE.with(|x| x.borrow_mut().propose_owner(caller(), owner))
+}
+
+#[update]
+fn accept_ownership() -> Result<u64, String> {
+    STATE.with(|x| x.borrow_mut().accept_ownership(caller()))
+}
+
+//Removes owner for good, nobody can grant or revoke admin role afterwards
+#[update(guard="owner_guard")]
+fn renounce_ownership() -> Result<u64, String> {
+    STATE.with(|x| x.borrow_mut().renounce_ownership(caller()))
+}
+
+#[query]
+fn pending_owner() -> Option<(Principal, u64)> {
+    STATE.with(|x| x.borrow().pending_owner)
+}
+
+#[query]
+fn roles_of(principal: Principal) -> Vec<Role> {
+    STATE.with(|x| x.borrow().roles.get(&principal).map_or_else(Vec::default, |x| x.iter().copied().collect()))
+}
+
+#[query]
+fn role_members(role: Role) -> Vec<Principal> {
+    STATE.with(|x| {
+        x.borrow().roles.iter()
+            .filter(|(_, roles)| roles.contains(&role))
+            .map(|(principal, _)| *principal)
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod test {
+use super::*;
+use crate::testing::*;
+
+    #[test]
+    fn test_roles() {
+        let mut state = get_state();
+
+        assert!(!state.has_role(user_b(), Role::minter));
+        assert_eq!(state.grant_role(user_a(), user_b(), Role::minter), Ok(0));
+        assert!(state.has_role(user_b(), Role::minter));
+        assert!(!state.has_role(user_b(), Role::pauser));
+        assert_eq!(state.grant_role(user_b(), ledger(), Role::admin), Err(String::from("Only owner can grant admin role")));
+
+        assert_eq!(state.revoke_role(user_a(), user_b(), Role::minter), Ok(1));
+        assert!(!state.has_role(user_b(), Role::minter));
+        assert_eq!(state.revoke_role(user_a(), user_b(), Role::minter), Err(String::from("Principal does not have this role")));
+    }
+
+    #[test]
+    fn test_ownership_handover() {
+        let mut state = get_state();
+
+        assert_eq!(state.propose_owner(user_b(), user_b()), Err(String::from("The caller is not the owner of contract")));
+        assert_eq!(state.propose_owner(user_a(), user_b()), Ok(0));
+        assert_eq!(state.accept_ownership(ledger()), Err(String::from("The caller is not the proposed owner")));
+        assert_eq!(state.owner, Some(user_a()));
+
+        assert_eq!(state.accept_ownership(user_b()), Ok(1));
+        assert_eq!(state.owner, Some(user_b()));
+        assert_eq!(state.pending_owner, None);
+
+        state.pending_owner = Some((user_a(), 0));
+        assert_eq!(state.accept_ownership(user_a()), Err(String::from("Ownership proposal expired")));
+
+        assert_eq!(state.renounce_ownership(user_b()), Ok(2));
+        assert_eq!(state.owner, None);
+    }
+
+    #[test]
+    fn test_renounce_revokes_admins() {
+        let mut state = get_state();
+
+        assert_eq!(state.grant_role(user_a(), user_b(), Role::admin), Ok(0));
+        assert_eq!(state.grant_role(user_a(), user_b(), Role::minter), Ok(1));
+        assert_eq!(state.grant_role(user_a(), ledger(), Role::admin), Ok(2));
+
+        //Every admin grant is revoked with its own ledger record before renounce record
+        assert_eq!(state.renounce_ownership(user_a()), Ok(5));
+        assert!(!state.has_role(user_b(), Role::pauser));
+        assert!(!state.has_role(ledger(), Role::admin));
+        assert!(state.has_role(user_b(), Role::minter));
+    }
+}
@@ -0,0 +1,151 @@
+use ic_cdk::export::candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::{query, update};
+use serde::Serialize;
+
+use common::RoyaltyShare;
+
+use crate::marketplace::Marketplace;
+use crate::token::State;
+use crate::guards::{fee_manager_guard};
+
+/// Basis points making up the whole sale price
+pub const MAX_BPS: u64 = 10000;
+
+/// Royalty table entry, bps is part of the sale price in basis points (100 = 1%)
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize, Serialize)]
+pub struct Royalty {
+    pub account: Principal,
+    pub bps: u64,
+}
+
+impl Royalty {
+    /// Converts entry to the 1/100000 fee unit used by creators_fee
+    pub fn share(&self) -> RoyaltyShare {
+        RoyaltyShare {
+            account: self.account,
+            fee: self.bps * 10,
+        }
+    }
+}
+
+/// Verifies that royalty table adds up to at most 100% of the sale price
+pub fn check_royalties(royalties: &[Royalty]) -> Result<(), String> {
+    let mut total: u64 = 0;
+
+    for item in royalties {
+        if item.bps == 0 { return Err(String::from("Royalty must be above 0 bps")); }
+        total = total.checked_add(item.bps).ok_or_else(|| String::from("Royalty overflow"))?;
+    }
+
+    if total > MAX_BPS { return Err(String::from("Royalties cannot exceed 100% of the sale price")); }
+
+    Ok(())
+}
+
+/// Calculates amount owed to every share of given price, fee is in 1/100000 of price
+pub fn split(price: u64, shares: &[RoyaltyShare]) -> Vec<(Principal, u64)> {
+    shares.iter().map(|x| (x.account, (price as u128 * x.fee as u128 / 100000) as u64)).collect()
+}
+
+#[query]
+fn royalties() -> Vec<Royalty> {
+    Marketplace::get().borrow().royalties.clone()
+}
+
+//Replaces royalty table, empty table falls back to creators_fee and creators_address
+#[update(guard="fee_manager_guard")]
+fn set_royalties(royalties: Vec<Royalty>) -> Result<(), String> {
+    check_royalties(&royalties)?;
+    Marketplace::get().borrow_mut().royalties = royalties;
+
+    Ok(())
+}
+
+//Sets royalty terms of single token, empty list removes the override
+#[update(guard="fee_manager_guard")]
+fn set_token_royalties(token_id: u32, royalties: Vec<Royalty>) -> Result<(), String> {
+    check_royalties(&royalties)?;
+
+    let state = State::get();
+    let mut state = state.borrow_mut();
+    //Token must have uploaded metadata or be minted
+    if !state.tokens.contains_key(&token_id) { state.check_token_id(token_id)?; }
+
+    if royalties.is_empty() {
+        state.token_royalties.remove(&token_id);
+    } else {
+        state.token_royalties.insert(token_id, royalties);
+    }
+
+    Ok(())
+}
+
+#[query]
+fn token_royalties(token_id: u32) -> Option<Vec<Royalty>> {
+    State::get().borrow().token_royalties.get(&token_id).cloned()
+}
+
+/// Returns every royalty recipient of token with the amount owed for sale_price
+#[query]
+fn royalty_splits(token_id: u32, sale_price: u64) -> Vec<(Principal, u64)> {
+    let shares = Marketplace::get().borrow().royalty_shares(token_id);
+    split(sale_price, &shares)
+}
+
+/// EIP-2981 style royalty query, returns the single recipient with the royalty for sale_price.
+/// Tokens with several recipients are rejected, marketplaces have to pay them with royalty_splits.
+#[query]
+fn royalty_info(token_id: u32, sale_price: u64) -> Result<(Principal, u64), String> {
+    let splits = royalty_splits(token_id, sale_price);
+
+    match splits.as_slice() {
+        [] => Ok((State::get().borrow().owner.unwrap_or_else(Principal::anonymous), 0)),
+        [single] => Ok(*single),
+        _ => Err(String::from("Token has several royalty recipients, use royalty_splits")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+use super::*;
+use crate::testing::*;
+
+    #[test]
+    fn test_check_royalties() {
+        let valid = vec![Royalty { account: user_a(), bps: 6000 }, Royalty { account: user_b(), bps: 4000 }];
+        assert_eq!(check_royalties(&valid), Ok(()));
+
+        let over = vec![Royalty { account: user_a(), bps: 6000 }, Royalty { account: user_b(), bps: 4001 }];
+        assert!(check_royalties(&over).is_err());
+    }
+
+    #[test]
+    fn test_split() {
+        let shares = vec![
+            Royalty { account: user_a(), bps: 250 }.share(),
+            Royalty { account: user_b(), bps: 125 }.share(),
+        ];
+
+        assert_eq!(split(100000000, &shares), vec![(user_a(), 2500000), (user_b(), 1250000)]);
+    }
+
+    #[test]
+    fn test_royalty_info_override() {
+        set_state();
+        set_marketplace();
+
+        Marketplace::get().borrow_mut().royalties = vec![Royalty { account: user_a(), bps: 500 }];
+        assert_eq!(royalty_info(1, 1000000), Ok((user_a(), 50000)));
+
+        State::get().borrow_mut().token_royalties.insert(1, vec![Royalty { account: user_b(), bps: 1000 }]);
+        assert_eq!(royalty_info(1, 1000000), Ok((user_b(), 100000)));
+        assert_eq!(royalty_info(2, 1000000), Ok((user_a(), 50000)));
+
+        //Several recipients cannot be reported as one
+        State::get().borrow_mut().token_royalties.insert(1, vec![
+            Royalty { account: user_b(), bps: 1000 },
+            Royalty { account: user_a(), bps: 1000 },
+        ]);
+        assert!(royalty_info(1, 1000000).is_err());
+    }
+}
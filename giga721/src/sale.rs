@@ -0,0 +1,271 @@
+use std::collections::{HashMap, HashSet};
+
+#[cfg(test)]
+use crate::testing::{time};
+#[cfg(not(test))]
+use ic_cdk::api::time;
+
+use ic_cdk::export::candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::{query, update};
+use serde::Serialize;
+
+use crate::marketplace::Marketplace;
+use crate::token::STATE;
+use crate::ledger::LEDGER;
+use crate::guards::{minter_guard};
+
+/// Set in payment memo of ICP transfers paying for sale mints, lower bits hold the quantity
+pub const MINT_MEMO_FLAG: u64 = 1 << 63;
+
+/// Maximum number of tokens minted with one payment
+pub const MAX_MINT_QUANTITY: u32 = 20;
+
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize, Serialize)]
+pub struct SalePhase {
+    pub name: String,
+    /// Only allowlisted principals can mint during this phase
+    pub allowlist_only: bool,
+    /// Price of one token in e8s
+    pub price: u64,
+    /// Phase start and end in nanoseconds, end 0 keeps the phase open
+    pub start: u64,
+    pub end: u64,
+    /// Mints per principal during this phase, 0 for no limit
+    pub wallet_limit: u32,
+}
+
+impl SalePhase {
+    fn is_active(&self, now: u64) -> bool {
+        self.start <= now && (self.end == 0 || now < self.end)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, CandidType, Deserialize, Serialize)]
+pub struct SaleConfig {
+    pub phases: Vec<SalePhase>,
+    /// Mints per principal over the whole sale, 0 for no limit
+    pub wallet_limit: u32,
+    /// Tokens kept out of the sale for mint_for
+    pub reserved: u32,
+    /// Receives sale proceeds, canister owner when not set
+    pub treasury: Option<Principal>,
+}
+
+#[derive(Clone, Default, CandidType, Deserialize, Serialize)]
+pub struct Sale {
+    pub config: SaleConfig,
+    pub allowlist: HashSet<Principal>,
+
+    /// Number of tokens minted through the sale
+    pub minted: u32,
+    pub minted_by: HashMap<Principal, u32>,
+    /// Mints per phase name and principal
+    pub phase_minted: HashMap<String, HashMap<Principal, u32>>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct SaleInfo {
+    pub config: SaleConfig,
+    pub active_phase: Option<SalePhase>,
+    pub minted: u32,
+    /// Tokens that can still be minted through the sale
+    pub available: u32,
+}
+
+impl Sale {
+    pub fn set_config(&mut self, config: SaleConfig) -> Result<(), String> {
+        if config.phases.iter().any(|x| x.end != 0 && x.end <= x.start) { return Err(String::from("Phase must end after it starts")); }
+        let max_supply = STATE.with(|x| x.borrow().max_supply);
+        if config.reserved > max_supply { return Err(String::from("Reserved supply exceeds max supply")); }
+
+        self.config = config;
+        Ok(())
+    }
+
+    /// Returns first phase open at given time
+    pub fn active_phase(&self, now: u64) -> Option<&SalePhase> {
+        self.config.phases.iter().find(|x| x.is_active(now))
+    }
+
+    /// Returns number of tokens the sale can still mint, reserved supply is left for the owner
+    pub fn available(&self) -> u32 {
+        let (max_supply, minted) = STATE.with(|x| {
+            let state = x.borrow();
+            (state.max_supply, state.minted_count())
+        });
+
+        let sale_supply = max_supply.saturating_sub(self.config.reserved).saturating_sub(self.minted);
+        sale_supply.min(max_supply.saturating_sub(minted))
+    }
+
+    pub fn minted_by(&self, buyer: Principal) -> u32 {
+        self.minted_by.get(&buyer).copied().unwrap_or(0)
+    }
+
+    /// Mints quantity tokens to buyer for paid e8s, returns first ledger block and total price
+    pub fn mint(&mut self, buyer: Principal, quantity: u32, paid: u64) -> Result<(u64, u64), String> {
+        if quantity == 0 || quantity > MAX_MINT_QUANTITY { return Err(format!("Quantity must be between 1 and {}", MAX_MINT_QUANTITY)); }
+
+        let phase = self.active_phase(time()).ok_or_else(|| String::from("Sale is not active"))?.clone();
+        if phase.allowlist_only && !self.allowlist.contains(&buyer) { return Err(String::from("Principal is not on the allowlist")); }
+
+        let minted_by = self.minted_by(buyer);
+        if self.config.wallet_limit > 0 && minted_by + quantity > self.config.wallet_limit { return Err(String::from("Mint limit per principal reached")); }
+
+        let phase_minted = self.phase_minted.get(&phase.name).and_then(|x| x.get(&buyer)).copied().unwrap_or(0);
+        if phase.wallet_limit > 0 && phase_minted + quantity > phase.wallet_limit { return Err(String::from("Mint limit per principal reached for this phase")); }
+
+        let price = phase.price.checked_mul(quantity as u64).ok_or_else(|| String::from("Price overflow"))?;
+        if price > paid { return Err(String::from("Sent amount does not satisfy mint price")); }
+
+        if quantity > self.available() { return Err(String::from("Not enough tokens left in the sale")); }
+
+        //First mint record will be written at this index
+        let block = LEDGER.with(|x| {
+            let ledger = x.borrow();
+            ledger.offset + ledger.tx.len() as u64
+        });
+
+        STATE.with(|x| {
+            let mut state = x.borrow_mut();
+            //Whole payment is refunded on error, so no token may be minted unless all of them can be
+            state.check_mintable(quantity)?;
+            for _ in 0..quantity {
+                state.mint(buyer, buyer)?;
+            }
+            Ok::<(), String>(())
+        })?;
+
+        self.minted += quantity;
+        *self.minted_by.entry(buyer).or_default() += quantity;
+        *self.phase_minted.entry(phase.name).or_default().entry(buyer).or_default() += quantity;
+
+        Ok((block, price))
+    }
+}
+
+#[query]
+fn sale() -> SaleInfo {
+    let market = Marketplace::get();
+    let sale = &market.borrow().sale;
+
+    SaleInfo {
+        config: sale.config.clone(),
+        active_phase: sale.active_phase(time()).cloned(),
+        minted: sale.minted,
+        available: sale.available(),
+    }
+}
+
+#[update(guard="minter_guard")]
+fn set_sale(config: SaleConfig) -> Result<(), String> {
+    Marketplace::get().borrow_mut().sale.set_config(config)
+}
+
+#[update(guard="minter_guard")]
+fn add_to_allowlist(principals: Vec<Principal>) -> u64 {
+    let market = Marketplace::get();
+    let allowlist = &mut market.borrow_mut().sale.allowlist;
+    allowlist.extend(principals);
+    allowlist.len() as u64
+}
+
+#[update(guard="minter_guard")]
+fn remove_from_allowlist(principals: Vec<Principal>) -> u64 {
+    let market = Marketplace::get();
+    let allowlist = &mut market.borrow_mut().sale.allowlist;
+    for principal in principals.iter() {
+        allowlist.remove(principal);
+    }
+    allowlist.len() as u64
+}
+
+#[query]
+fn is_allowlisted(principal: Principal) -> bool {
+    Marketplace::get().borrow().sale.allowlist.contains(&principal)
+}
+
+//Returns number of tokens minted by principal through the sale
+#[query]
+fn sale_minted(principal: Principal) -> u32 {
+    Marketplace::get().borrow().sale.minted_by(principal)
+}
+
+//Returns memo to use when sending ICP for minting quantity tokens
+#[query]
+fn mint_memo(quantity: u32) -> u64 {
+    MINT_MEMO_FLAG | quantity as u64
+}
+
+#[cfg(test)]
+mod test {
+use super::*;
+use crate::testing::*;
+
+    fn phase(allowlist_only: bool, wallet_limit: u32) -> SalePhase {
+        SalePhase {
+            name: String::from("presale"),
+            allowlist_only,
+            price: 100000000,
+            start: 0,
+            end: 0,
+            wallet_limit,
+        }
+    }
+
+    #[test]
+    fn test_sale_mint() {
+        set_state();
+        let mut sale = Sale::default();
+
+        assert_eq!(sale.mint(user_a(), 1, 100000000), Err(String::from("Sale is not active")));
+
+        sale.set_config(SaleConfig { phases: vec![phase(true, 2)], wallet_limit: 3, reserved: 0, treasury: None }).unwrap();
+        assert_eq!(sale.mint(user_a(), 1, 100000000), Err(String::from("Principal is not on the allowlist")));
+
+        sale.allowlist.insert(user_a());
+        assert_eq!(sale.mint(user_a(), 2, 100000000), Err(String::from("Sent amount does not satisfy mint price")));
+        assert_eq!(sale.mint(user_a(), 2, 200000000), Ok((0, 200000000)));
+        assert_eq!(sale.mint(user_a(), 1, 100000000), Err(String::from("Mint limit per principal reached for this phase")));
+
+        assert_eq!(sale.minted_by(user_a()), 2);
+        assert_eq!(STATE.with(|x| x.borrow().owners.get(&user_a()).map(|x| x.len())), Some(2));
+        assert_eq!(LEDGER.with(|x| x.borrow().tx.len()), 2);
+    }
+
+    #[test]
+    fn test_sale_reserved_supply() {
+        set_state();
+        let max_supply = STATE.with(|x| x.borrow().max_supply);
+        let mut sale = Sale::default();
+        sale.set_config(SaleConfig { phases: vec![phase(false, 0)], wallet_limit: 0, reserved: max_supply - 1, treasury: None }).unwrap();
+
+        assert_eq!(sale.available(), 1);
+        assert_eq!(sale.mint(user_b(), 2, 200000000), Err(String::from("Not enough tokens left in the sale")));
+        assert_eq!(sale.mint(user_b(), 1, 100000000), Ok((0, 100000000)));
+        assert_eq!(sale.available(), 0);
+    }
+
+    #[test]
+    fn test_sale_mint_all_or_nothing() {
+        set_state();
+        let mut sale = Sale::default();
+        sale.set_config(SaleConfig { phases: vec![phase(false, 0)], wallet_limit: 0, reserved: 0, treasury: None }).unwrap();
+
+        //Fair mint without seed fails before the first token is minted
+        STATE.with(|x| x.borrow_mut().fair_mint = true);
+        assert_eq!(sale.mint(user_a(), 2, 200000000), Err(String::from("Mint seed not initialized")));
+        assert_eq!(STATE.with(|x| x.borrow().token_owners.len()), 0);
+        assert_eq!(LEDGER.with(|x| x.borrow().tx.len()), 0);
+        assert_eq!(sale.minted_by(user_a()), 0);
+
+        //Token id 0 does not take a sale id, free ids are counted within 1..=max_supply
+        STATE.with(|x| x.borrow_mut().fair_mint = false);
+        let max_supply = STATE.with(|x| x.borrow().max_supply);
+        for token_id in 0..max_supply - 1 {
+            let _ = STATE.with(|x| x.borrow_mut().mint_token_id(user_b(), user_b(), token_id));
+        }
+        assert_eq!(STATE.with(|x| x.borrow().check_mintable(2)), Err(String::from("Max token count reached")));
+        assert_eq!(STATE.with(|x| x.borrow().check_mintable(1)), Ok(()));
+    }
+}
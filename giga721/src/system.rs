@@ -5,7 +5,7 @@ use crate::token::State;
 
 use ic_cdk::export::candid::Principal;
 use ic_cdk::print;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use ic_cdk_macros::{init, post_upgrade, pre_upgrade};
 
@@ -24,7 +24,26 @@ fn init(name: String, symbol: String, desc: String, max_supply: i128, owner: Pri
 
         tokens: HashMap::default(),
         token_owners: HashMap::default(),
+        burned: HashSet::default(),
         owners: HashMap::default(),
+        token_royalties: HashMap::default(),
+        fair_mint: false,
+        mint_seed: Vec::default(),
+        metadata_hidden: false,
+        placeholder: None,
+        provenance: None,
+        metadata_updaters: HashSet::default(),
+        metadata_versions: HashMap::default(),
+        editable_properties: HashMap::default(),
+        holder_properties: HashMap::default(),
+        soulbound: false,
+        soulbound_tokens: HashSet::default(),
+        roles: HashMap::default(),
+        pending_owner: None,
+        transfers_paused: false,
+        marketplace_paused: false,
+        minting_paused: false,
+        pause_reason: None,
     };
 
     *State::get().borrow_mut() = state;
@@ -59,6 +78,8 @@ fn post_upgrade() {
     *State::get().borrow_mut() = state;
     *Ledger::get().borrow_mut() = ledger;
     *Marketplace::get().borrow_mut() = market;
+    Marketplace::get().borrow_mut().rebuild_index();
+    State::get().borrow_mut().rebuild_burned();
 }
 
 #[cfg(test)]
@@ -128,7 +149,7 @@ use super::*;
         };
 
         STATE.with(|x| {
-            x.borrow_mut().store_tokens(&vec![token; 10000]);
+            x.borrow_mut().store_tokens(&vec![token; 10000]).unwrap();
         });
 
         pre_upgrade();
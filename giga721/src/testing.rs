@@ -3,7 +3,7 @@ use common::rc_bytes::RcBytes;
 use crate::storage::Asset;
 use std::cell::RefCell;
 use std::io;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 
 use ic_cdk::api::stable::StableMemoryError;
 
@@ -11,8 +11,10 @@ use ic_cdk::export::candid::{Principal};
 
 use crate::token::State;
 use crate::marketplace::{Marketplace, Stats};
+use crate::fees::FeeConfig;
+use crate::sale::Sale;
 
-use common::SendArgs;
+use common::{SendArgs, TransferArg, TransferFromArgs};
 use ic_cdk::export::candid::{encode_args};
 
 pub fn trap(data: &str) {
@@ -44,7 +46,26 @@ pub fn get_state() -> State {
 
         tokens: HashMap::default(),
         token_owners: HashMap::default(),
-        owners: HashMap::default()
+        burned: HashSet::default(),
+        owners: HashMap::default(),
+        token_royalties: HashMap::default(),
+        fair_mint: false,
+        mint_seed: Vec::default(),
+        metadata_hidden: false,
+        placeholder: None,
+        provenance: None,
+        metadata_updaters: HashSet::default(),
+        metadata_versions: HashMap::default(),
+        editable_properties: HashMap::default(),
+        holder_properties: HashMap::default(),
+        soulbound: false,
+        soulbound_tokens: HashSet::default(),
+        roles: HashMap::default(),
+        pending_owner: None,
+        transfers_paused: false,
+        marketplace_paused: false,
+        minting_paused: false,
+        pause_reason: None,
     }
 }
 
@@ -61,6 +82,7 @@ pub fn get_marketplace() -> Marketplace {
     Marketplace {
         creators_fee: 2500,
         creators_address: Some(owner),
+        royalties: Vec::default(),
 
         notify_canister: Some(ledger),
 
@@ -68,11 +90,18 @@ pub fn get_marketplace() -> Marketplace {
 
         tx_enabled: true,
         listings: HashMap::default(),
+        price_index: BTreeSet::default(),
 
         // payment_offset: 0,
         listing_offset: 0,
         // payments: Vec::default(),
-        stats: Stats::default()
+        stats: Stats::default(),
+        fees: FeeConfig::default(),
+        sale: Sale::default(),
+        reservations: HashMap::default(),
+        reservation_window: 0,
+        balances: HashMap::default(),
+        payment_tokens: vec![ledger].into_iter().collect(),
     }
 }
 
@@ -255,4 +284,23 @@ impl io::Read for StableReader {
         self.read(buf)
             .map_err(|_| io::Error::new(io::ErrorKind::Other, "Unexpected error."))
     }
-}
\ No newline at end of file
+}
+pub async fn call_icrc1_fee(_canister: Principal) -> Result<u64, String> {
+    Ok(10000)
+}
+
+pub async fn call_icrc1_transfer(_canister: Principal, _args: &TransferArg) -> Result<u64, String> {
+    Ok(0)
+}
+
+pub async fn call_icrc2_transfer_from(_canister: Principal, _args: &TransferFromArgs) -> Result<u64, String> {
+    Ok(0)
+}
+
+pub async fn call_raw_rand() -> Result<Vec<u8>, String> {
+    Ok(vec![7; 32])
+}
+
+pub fn id() -> Principal {
+    Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap()
+}
@@ -1,14 +1,18 @@
 use std::rc::Rc;
 use std::cell::RefCell;
-use std::collections::HashMap;
-use common::{Property, HeaderField};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use common::{Operation, Property, HeaderField};
 
 use ic_cdk::export::candid::{CandidType, Deserialize, Principal};
 
 use crate::ledger::{LEDGER};
 use crate::marketplace::{MARKETPLACE};
+use crate::royalty::Royalty;
+use crate::roles::Role;
 
 use serde::Serialize;
+use sha2::{Digest, Sha256};
 
 
 thread_local! {
@@ -90,9 +94,89 @@ pub struct State {
     pub tokens: HashMap<u32, Token>, 
     /// Stores token ownership, this contains minted tokens
     pub token_owners: HashMap<u32, Principal>,
+    /// Burned token ids, they count towards max_supply and are never minted again
+    #[serde(default)]
+    pub burned: HashSet<u32>,
 
     /// List of owners, with list of tokens
     pub owners: HashMap<Principal, Vec<u128>>,
+
+    /// Royalty terms of single tokens, overriding the collection royalty table
+    #[serde(default)]
+    pub token_royalties: HashMap<u32, Vec<Royalty>>,
+
+    /// Draws minted token ids at random from unminted ids
+    #[serde(default)]
+    pub fair_mint: bool,
+    /// Fair mint seed, refreshed from raw_rand before each sale mint and never exposed
+    #[serde(default)]
+    pub mint_seed: Vec<u8>,
+    /// Token metadata is hidden until owner calls reveal
+    #[serde(default)]
+    pub metadata_hidden: bool,
+    /// Shown in place of token metadata while it is hidden, name gets token id appended
+    #[serde(default)]
+    pub placeholder: Option<Token>,
+    /// Hex sha256 of token metadata committed before minting, see metadata_hash
+    #[serde(default)]
+    pub provenance: Option<String>,
+
+    /// Principals allowed to update token metadata besides the owner
+    #[serde(default)]
+    pub metadata_updaters: HashSet<Principal>,
+    /// Number of metadata updates of each token, missing entry is version 0
+    #[serde(default)]
+    pub metadata_versions: HashMap<u32, u64>,
+
+    /// Property names token holders may set, with maximum value length in characters
+    #[serde(default)]
+    pub editable_properties: HashMap<String, u32>,
+    /// Properties set by token holders, kept apart from uploaded metadata
+    #[serde(default)]
+    pub holder_properties: HashMap<u32, Vec<Property>>,
+
+    /// Makes every token of collection non-transferable
+    #[serde(default)]
+    pub soulbound: bool,
+    /// Non-transferable tokens of otherwise transferable collection
+    #[serde(default)]
+    pub soulbound_tokens: HashSet<u32>,
+
+    /// Roles granted besides owner, see has_role
+    #[serde(default)]
+    pub roles: HashMap<Principal, HashSet<Role>>,
+    /// Proposed owner and proposal expiry, ownership moves when proposed owner accepts
+    #[serde(default)]
+    pub pending_owner: Option<(Principal, u64)>,
+
+    /// Pause switches of single areas, is_paused stops all of them
+    #[serde(default)]
+    pub transfers_paused: bool,
+    #[serde(default)]
+    pub marketplace_paused: bool,
+    #[serde(default)]
+    pub minting_paused: bool,
+    /// Shown in errors and pause_status while paused
+    #[serde(default)]
+    pub pause_reason: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, CandidType, Deserialize)]
+#[allow(non_camel_case_types)]
+pub enum PauseSwitch {
+    all,
+    transfers,
+    marketplace,
+    minting,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct PauseStatus {
+    pub paused: bool,
+    pub transfers: bool,
+    pub marketplace: bool,
+    pub minting: bool,
+    pub reason: Option<String>,
 }
 
 impl State {
@@ -100,17 +184,172 @@ impl State {
         STATE.with(|x| x.clone())
     }
 
+    /// Returns error if canister or given area is paused
+    pub fn check_not_paused(&self, switch: PauseSwitch) -> Result<(), String> {
+        let paused = self.is_paused || match switch {
+            PauseSwitch::all => false,
+            PauseSwitch::transfers => self.transfers_paused,
+            PauseSwitch::marketplace => self.marketplace_paused,
+            PauseSwitch::minting => self.minting_paused,
+        };
+
+        if !paused { return Ok(()); }
+
+        match &self.pause_reason {
+            Some(reason) => Err(format!("Canister is paused, {}", reason)),
+            None => Err(String::from("Canister is paused, try another time")),
+        }
+    }
+
+    pub fn set_pause(&mut self, switch: PauseSwitch, paused: bool) {
+        match switch {
+            PauseSwitch::all => self.is_paused = paused,
+            PauseSwitch::transfers => self.transfers_paused = paused,
+            PauseSwitch::marketplace => self.marketplace_paused = paused,
+            PauseSwitch::minting => self.minting_paused = paused,
+        }
+    }
+
+    pub fn pause_status(&self) -> PauseStatus {
+        PauseStatus {
+            paused: self.is_paused,
+            transfers: self.is_paused || self.transfers_paused,
+            marketplace: self.is_paused || self.marketplace_paused,
+            minting: self.is_paused || self.minting_paused,
+            reason: self.pause_reason.clone(),
+        }
+    }
+
     ///Stores token metadata for all tokens
-    pub fn store_tokens(&mut self, tokens: &Vec<Token>) {
+    pub fn store_tokens(&mut self, tokens: &Vec<Token>) -> Result<(), String> {
+        self.check_metadata_mutable()?;
+
         for i in tokens {
             self.tokens.insert(i.id as u32, i.clone());
         }
+
+        Ok(())
+    }
+
+    /// Returns error once metadata committed by provenance hash was revealed
+    fn check_metadata_mutable(&self) -> Result<(), String> {
+        if self.provenance.is_some() && !self.metadata_hidden { return Err(String::from("Metadata is fixed by provenance hash")); }
+        Ok(())
+    }
+
+    /// Returns token metadata, None while metadata is hidden
+    pub fn revealed_token(&self, token_id: u32) -> Option<&Token> {
+        if self.metadata_hidden { return None; }
+        self.tokens.get(&token_id)
+    }
+
+    /// Replaces metadata of single token, caller must be the owner of contract or authorized updater.
+    /// Returns ledger block of the change
+    pub fn update_metadata(&mut self, caller: Principal, token: Token) -> Result<u64, String> {
+        if !self.has_role(caller, Role::metadata_uploader) && !self.metadata_updaters.contains(&caller) { return Err(String::from("The caller is not allowed to update metadata")); }
+
+        self.check_metadata_mutable()?;
+
+        let token_id = u32::try_from(token.id).map_err(|_| String::from("Token id outside of estabished bounds"))?;
+        if token_id == 0 || token_id > self.max_supply { return Err("Token id outside of estabished bounds".to_string()); }
+        if !self.tokens.contains_key(&token_id) { return Err(String::from("Could not find token")); }
+
+        self.tokens.insert(token_id, token);
+
+        let version = self.metadata_versions.entry(token_id).or_default();
+        *version += 1;
+        let version = *version;
+
+        Ok(LEDGER.with(|x| x.borrow_mut().update_metadata(caller, token_id, version)))
+    }
+
+    /// Returns placeholder metadata of token
+    pub fn placeholder_of(&self, token_id: u32) -> Token {
+        match &self.placeholder {
+            Some(placeholder) => Token {
+                id: token_id as u128,
+                url: placeholder.url.clone(),
+                name: format!("{} #{}", placeholder.name, token_id),
+                desc: placeholder.desc.clone(),
+                properties: placeholder.properties.clone(),
+            },
+            None => Token {
+                id: token_id as u128,
+                url: String::default(),
+                name: format!("{} #{}", self.name, token_id),
+                desc: self.description.clone(),
+                properties: Vec::default(),
+            },
+        }
+    }
+
+    /// Returns hex sha256 of token metadata ordered by id, one line per token:
+    /// id|url|name|desc|name=value,name=value
+    pub fn metadata_hash(&self) -> String {
+        let mut ids: Vec<&u32> = self.tokens.keys().collect();
+        ids.sort();
+
+        let mut hasher = Sha256::new();
+        for id in ids {
+            let token = &self.tokens[id];
+            let properties: Vec<String> = token.properties.iter().map(|p| format!("{}={}", p.name, p.value)).collect();
+            hasher.update(format!("{}|{}|{}|{}|{}\n", token.id, token.url, token.name, token.desc, properties.join(",")).as_bytes());
+        }
+
+        hex::encode(hasher.finalize())
+    }
+
+    /// Switches fair mint mode, metadata can be hidden only before minting starts and made public only with reveal
+    pub fn set_fair_mint(&mut self, enabled: bool, hide_metadata: bool) -> Result<(), String> {
+        if hide_metadata != self.metadata_hidden {
+            if !hide_metadata { return Err(String::from("Hidden metadata can be made public only with reveal")); }
+            if !self.token_owners.is_empty() { return Err(String::from("Metadata can be hidden only before minting starts")); }
+        }
+
+        self.fair_mint = enabled;
+        self.metadata_hidden = hide_metadata;
+        Ok(())
+    }
+
+    /// Commits provenance hash, it can be set only while metadata is hidden and before minting starts
+    pub fn set_provenance(&mut self, hash: String) -> Result<(), String> {
+        //Revealed metadata is public, commitment made after reveal would prove nothing
+        if !self.metadata_hidden { return Err(String::from("Provenance can be set only while metadata is hidden")); }
+        if !self.token_owners.is_empty() { return Err(String::from("Provenance can be set only before minting starts")); }
+        if hash.len() != 64 || hex::decode(&hash).is_err() { return Err(String::from("Provenance must be hex encoded sha256 hash")); }
+
+        self.provenance = Some(hash.to_lowercase());
+        Ok(())
+    }
+
+    /// Reveals hidden metadata, uploaded metadata must match committed provenance hash
+    pub fn reveal(&mut self) -> Result<(), String> {
+        if !self.metadata_hidden { return Err(String::from("Metadata is already revealed")); }
+
+        if let Some(provenance) = &self.provenance {
+            if *provenance != self.metadata_hash() { return Err(String::from("Uploaded metadata does not match provenance hash")); }
+        }
+
+        self.metadata_hidden = false;
+        Ok(())
     }
 
     //Returns data of token
     pub fn data_of(&mut self, token_id: u32) -> Result<TokenDesc, String> {
         self.check_token_id(token_id)?;
 
+        if self.metadata_hidden {
+            let data = self.placeholder_of(token_id);
+            return Ok(TokenDesc {
+                id: data.id,
+                url: data.url,
+                name: data.name,
+                desc: data.desc,
+                properties: self.with_holder_properties(token_id, data.properties),
+                owner: self.get_owner(token_id).unwrap()
+            });
+        }
+
         let data = self.tokens.get(&token_id).ok_or_else(|| String::from("Could not find token"))?;
 
         let item = TokenDesc {
@@ -118,13 +357,39 @@ impl State {
             url: data.url.clone(),
             name: data.name.clone(),
             desc: data.desc.clone(),
-            properties: data.properties.clone(),
+            properties: self.with_holder_properties(token_id, data.properties.clone()),
             owner: self.get_owner(token_id).unwrap()
         };
 
         Ok(item)
     }
 
+    /// Adds holder set properties to token properties, holder value replaces property with the same name
+    fn with_holder_properties(&self, token_id: u32, mut properties: Vec<Property>) -> Vec<Property> {
+        if let Some(holder) = self.holder_properties.get(&token_id) {
+            properties.retain(|p| !holder.iter().any(|h| h.name == p.name));
+            properties.extend(holder.iter().cloned());
+        }
+        properties
+    }
+
+    /// Sets whitelisted property of token, caller must own the token. Empty value removes the property.
+    /// Returns ledger block of the change
+    pub fn set_holder_property(&mut self, caller: Principal, token_id: u32, property: Property) -> Result<u64, String> {
+        self.check_owner(token_id, caller)?;
+
+        let max_length = *self.editable_properties.get(&property.name).ok_or_else(|| String::from("Property is not editable"))?;
+        if property.value.chars().count() > max_length as usize { return Err(format!("Property value is longer than {} characters", max_length)); }
+        if property.value.chars().any(|c| c.is_control()) { return Err(String::from("Property value contains invalid characters")); }
+
+        let properties = self.holder_properties.entry(token_id).or_default();
+        properties.retain(|p| p.name != property.name);
+        if !property.value.is_empty() { properties.push(property); }
+        if properties.is_empty() { self.holder_properties.remove(&token_id); }
+
+        Ok(LEDGER.with(|x| x.borrow_mut().set_property(caller, token_id)))
+    }
+
     ///Assigns token to given principal, use with caution
     pub fn assign_to(&mut self, to: Principal, token_id: u32) {
         let list = self.owners.get_mut(&to);
@@ -156,35 +421,93 @@ impl State {
         }
     }
 
-    /// Mints new token. Returns id of minted_token
-    #[allow(dead_code)]
-    pub fn mint(&mut self, caller: Principal) -> Result<u32, String> {
-        if self.token_owners.len() as u32 >= self.max_supply { return Err("Max token count reached".to_string()); }
+    /// Hashes new entropy into fair mint seed
+    pub fn mix_mint_seed(&mut self, entropy: &[u8]) {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.mint_seed);
+        hasher.update(entropy);
+        self.mint_seed = hasher.finalize().to_vec();
+    }
+
+    /// Returns number of tokens ever minted, burned tokens included
+    pub fn minted_count(&self) -> u32 {
+        (self.token_owners.len() + self.burned.len()) as u32
+    }
+
+    /// Returns true if token id was never minted
+    fn is_unminted(&self, token_id: u32) -> bool {
+        !self.token_owners.contains_key(&token_id) && !self.burned.contains(&token_id)
+    }
+
+    /// Fills burned ids from ledger burn records of canisters upgraded from versions without them
+    pub fn rebuild_burned(&mut self) {
+        if !self.burned.is_empty() { return; }
+
+        let burned: Vec<u32> = LEDGER.with(|x| {
+            x.borrow().tx.iter().filter(|r| matches!(r.op, Operation::burn)).map(|r| r.token_id).collect()
+        });
+        //Ids minted again after burn by previous versions are owned, not burned
+        self.burned = burned.into_iter().filter(|x| !self.token_owners.contains_key(x)).collect();
+    }
+
+    /// Returns error if quantity tokens cannot be minted with mint, so batch mints fail before any token is minted
+    pub fn check_mintable(&self, quantity: u32) -> Result<(), String> {
+        if self.fair_mint && self.mint_seed.is_empty() { return Err("Mint seed not initialized".to_string()); }
+
+        let free = (1..=self.max_supply).filter(|x| self.is_unminted(*x)).take(quantity as usize).count() as u32;
+        if free < quantity || self.minted_count() + quantity > self.max_supply { return Err("Max token count reached".to_string()); }
+
+        Ok(())
+    }
+
+    /// Returns next token id to mint, lowest unminted id or random unminted id in fair mint mode
+    fn next_token_id(&mut self) -> Result<u32, String> {
+        if !self.fair_mint {
+            return (1..=self.max_supply).find(|x| self.is_unminted(*x))
+                .ok_or_else(|| "Max token count reached".to_string());
+        }
+
+        if self.mint_seed.is_empty() { return Err("Mint seed not initialized".to_string()); }
+
+        let unminted: Vec<u32> = (1..=self.max_supply).filter(|x| self.is_unminted(*x)).collect();
+        if unminted.is_empty() { return Err("Max token count reached".to_string()); }
+
+        //Seed is advanced with every draw, so tokens minted with one payment differ
+        let supply = self.total_supply.to_be_bytes();
+        self.mix_mint_seed(&supply);
+
+        let mut draw = [0u8; 8];
+        draw.copy_from_slice(&self.mint_seed[..8]);
+
+        Ok(unminted[(u64::from_be_bytes(draw) % unminted.len() as u64) as usize])
+    }
+
+    /// Mints next token id to @to. Returns id of minted_token
+    pub fn mint(&mut self, caller: Principal, to: Principal) -> Result<u32, String> {
+        if self.minted_count() >= self.max_supply { return Err("Max token count reached".to_string()); }
     
-        let token_id = self.total_supply+1;
-        
-        if self.token_owners.contains_key(&token_id) { return Err("Could not mint token that is already taken".to_string()); }
+        let token_id = self.next_token_id()?;
 
         //Mint token
-        self.token_owners.insert(token_id, caller);
+        self.token_owners.insert(token_id, to);
 
         //Add minted token to owner
-        self.assign_to(caller, token_id);
+        self.assign_to(to, token_id);
 
         //Increase number of minted tokens
         self.total_supply += 1;
 
-        LEDGER.with(|x| x.borrow_mut().mint(caller, caller, token_id));
+        LEDGER.with(|x| x.borrow_mut().mint(caller, to, token_id));
 
         Ok(token_id)
     }
 
     pub fn mint_token_id(&mut self, caller: Principal, to: Principal, token_id: u32) -> Result<u64, String> {
-        if self.token_owners.len() as u32 >= self.max_supply { return Err("Max token count reached".to_string()); }
+        if self.minted_count() >= self.max_supply { return Err("Max token count reached".to_string()); }
     
         if token_id < 0 as u32 || token_id > self.max_supply { return Err("Token id outside of estabished bounds".to_string())}
 
-        if self.token_owners.contains_key(&token_id) { return Err("Could not mint token that is already taken".to_string()); }
+        if !self.is_unminted(token_id) { return Err("Could not mint token that is already taken".to_string()); }
 
         //Mint token
         self.token_owners.insert(token_id, to);
@@ -208,6 +531,7 @@ impl State {
         self.token_owners.remove(&token_id).ok_or_else(|| "Canot remove token owner".to_string())?;
 
         self.remove_from(caller, token_id);
+        self.burned.insert(token_id);
 
         //Decrease number of minted tokens
         self.total_supply -= 1;
@@ -264,6 +588,12 @@ impl State {
         self.assign_to(to, token_id);
     }
 
+    /// Checks if token can be moved by its holder, soulbound tokens can only be minted and burned
+    pub fn check_transferable(&self, token_id: u32) -> Result<(), String> {
+        if self.soulbound || self.soulbound_tokens.contains(&token_id) { return Err(String::from("Token is not transferable")); }
+        Ok(())
+    }
+
     /// Transfers token between accounts
     pub fn transfer(&mut self, from: Principal, to: Principal, token_id: u32) -> Result<u64, String> {
         //Check if token_id is between 0 and max_supply
@@ -272,7 +602,9 @@ impl State {
         //Check if token was minted and sender is the owner of token_id
         self.check_owner(token_id, from)?;
 
-        //First take off listing, ignore if not possible (it was not listed)
+        self.check_transferable(token_id)?;
+
+        //First take off listing and its reservation, ignore if not possible (it was not listed)
         let _ = MARKETPLACE.with(|x| x.borrow_mut().delist(from, token_id));
 
         //Update owner table
@@ -283,6 +615,16 @@ impl State {
 
         return Ok(block);
     }
+
+    /// Moves non-transferable token to new holder, used by owner of contract to recover lost badges
+    pub fn recover(&mut self, caller: Principal, to: Principal, token_id: u32) -> Result<u64, String> {
+        let from = *self.token_owners.get(&token_id).ok_or_else(|| String::from("Token not minted"))?;
+        if self.check_transferable(token_id).is_ok() { return Err(String::from("Only non-transferable tokens can be recovered")); }
+
+        self.moved(from, to, token_id);
+
+        Ok(LEDGER.with(|x| x.borrow_mut().recover(caller, from, to, token_id)))
+    }
 } 
 
 #[cfg(test)]
@@ -297,7 +639,7 @@ use crate::testing::*;
         let prin = Principal::from_text("tushn-jfas4-lrw4y-d3hun-lyc2x-hr2o2-2spfo-ak45s-jzksj-fzvln-yqe").unwrap();
         let to = Principal::from_text("tushn-jfas4-lrw4y-d3hun-lyc2x-hr2o2-2spfo-ak45s-jzksj-fzvln-yqe").unwrap();
         let token_id = 1 as u32;
-        let mint_result = state.mint(prin);
+        let mint_result = state.mint(prin, prin);
 
         STATE.with(|x| *x.borrow_mut() = state);
 
@@ -314,7 +656,7 @@ use crate::testing::*;
         let mut state = get_state();
         let prin = Principal::from_text("tushn-jfas4-lrw4y-d3hun-lyc2x-hr2o2-2spfo-ak45s-jzksj-fzvln-yqe").unwrap();
 
-        let mint_result = state.mint(prin);
+        let mint_result = state.mint(prin, prin);
 
         assert_eq!(mint_result, Ok(1));
 
@@ -322,12 +664,142 @@ use crate::testing::*;
         assert_eq!(len, 1);
     }
 
+    #[test]
+    fn test_fair_mint() {
+        let mut state = get_state();
+        state.fair_mint = true;
+        state.metadata_hidden = true;
+
+        assert_eq!(state.mint(user_a(), user_a()), Err("Mint seed not initialized".to_string()));
+
+        state.mix_mint_seed(&[7; 32]);
+        let first = state.mint(user_a(), user_a()).unwrap();
+        let second = state.mint(user_a(), user_a()).unwrap();
+
+        assert_ne!(first, second);
+        assert!(first >= 1 && first <= state.max_supply);
+
+        let data = state.data_of(first).unwrap();
+        assert_eq!(data.url, "");
+        assert!(data.properties.is_empty());
+    }
+
+    #[test]
+    fn test_set_fair_mint() {
+        let mut state = get_state();
+
+        assert_eq!(state.set_fair_mint(true, true), Ok(()));
+        assert_eq!(state.set_fair_mint(true, false), Err(String::from("Hidden metadata can be made public only with reveal")));
+
+        state.mix_mint_seed(&[7; 32]);
+        let _ = state.mint(user_a(), user_a()).unwrap();
+
+        //Fair mint can be switched after minting started, hidden flag cannot
+        assert_eq!(state.set_fair_mint(false, true), Ok(()));
+        assert_eq!(state.set_fair_mint(false, false), Err(String::from("Hidden metadata can be made public only with reveal")));
+        assert_eq!(state.reveal(), Ok(()));
+        assert_eq!(state.set_fair_mint(false, true), Err(String::from("Metadata can be hidden only before minting starts")));
+        assert!(!state.metadata_hidden);
+    }
+
+    #[test]
+    fn test_reveal_provenance() {
+        let mut state = get_state();
+        state.tokens.insert(1, Token { id: 1, url: String::from("1.png"), name: String::from("One"), desc: String::default(), properties: vec![] });
+
+        let hash = state.metadata_hash();
+        assert_eq!(state.set_provenance(hash.clone()), Err(String::from("Provenance can be set only while metadata is hidden")));
+
+        state.metadata_hidden = true;
+        assert_eq!(state.set_provenance(hash.clone()), Ok(()));
+
+        state.tokens.get_mut(&1).unwrap().url = String::from("2.png");
+        assert_eq!(state.reveal(), Err(String::from("Uploaded metadata does not match provenance hash")));
+
+        state.tokens.get_mut(&1).unwrap().url = String::from("1.png");
+        assert_eq!(state.reveal(), Ok(()));
+        assert_eq!(state.store_tokens(&vec![]), Err(String::from("Metadata is fixed by provenance hash")));
+        assert_eq!(state.set_provenance(hash), Err(String::from("Provenance can be set only while metadata is hidden")));
+    }
+
+    #[test]
+    fn test_update_metadata() {
+        let mut state = get_state();
+        let token = Token { id: 1, url: String::from("1.png"), name: String::from("One"), desc: String::default(), properties: vec![] };
+        state.store_tokens(&vec![token.clone()]).unwrap();
+
+        assert_eq!(state.update_metadata(user_b(), token.clone()), Err(String::from("The caller is not allowed to update metadata")));
+
+        state.metadata_updaters.insert(user_b());
+        assert_eq!(state.update_metadata(user_b(), Token { url: String::from("2.png"), ..token.clone() }), Ok(0));
+        assert_eq!(state.update_metadata(user_a(), token.clone()), Ok(1));
+
+        assert_eq!(state.metadata_versions.get(&1), Some(&2));
+        assert_eq!(state.tokens[&1].url, "1.png");
+        assert_eq!(LEDGER.with(|x| x.borrow().tx[1].memo), 2);
+
+        //Id is not truncated to a stored token
+        assert_eq!(state.update_metadata(user_a(), Token { id: (1u128 << 32) + 1, ..token.clone() }), Err(String::from("Token id outside of estabished bounds")));
+
+        //Revealed metadata is fixed by provenance hash
+        state.provenance = Some(state.metadata_hash());
+        assert_eq!(state.update_metadata(user_a(), token), Err(String::from("Metadata is fixed by provenance hash")));
+    }
+
+    #[test]
+    fn test_holder_property() {
+        let mut state = get_state();
+        state.mint_token_id(user_a(), user_b(), 1).unwrap();
+        state.editable_properties.insert(String::from("name"), 8);
+
+        let property = |name: &str, value: &str| Property { name: String::from(name), value: String::from(value) };
+
+        assert_eq!(state.set_holder_property(user_a(), 1, property("name", "Bob")), Err(String::from("This token does not belong to caller")));
+        assert_eq!(state.set_holder_property(user_b(), 1, property("bio", "Bob")), Err(String::from("Property is not editable")));
+        assert_eq!(state.set_holder_property(user_b(), 1, property("name", "Bob the great")), Err(String::from("Property value is longer than 8 characters")));
+        assert_eq!(state.set_holder_property(user_b(), 1, property("name", "Bob\n")), Err(String::from("Property value contains invalid characters")));
+        assert_eq!(state.set_holder_property(user_b(), 1, property("name", "Bob")), Ok(1));
+
+        state.metadata_hidden = true;
+        let data = state.data_of(1).unwrap();
+        assert_eq!(data.properties.len(), 1);
+        assert_eq!(data.properties[0].value, "Bob");
+    }
+
+    #[test]
+    fn test_soulbound() {
+        let mut state = get_state();
+        state.mint_token_id(user_a(), user_a(), 1).unwrap();
+
+        assert_eq!(state.recover(user_a(), user_b(), 1), Err(String::from("Only non-transferable tokens can be recovered")));
+
+        state.soulbound_tokens.insert(1);
+        assert_eq!(state.transfer(user_a(), user_b(), 1), Err(String::from("Token is not transferable")));
+        assert_eq!(state.recover(user_a(), user_b(), 1), Ok(1));
+        assert_eq!(state.check_owner(1, user_b()), Ok(user_b()));
+        assert_eq!(state.burn(user_b(), 1), Ok(2));
+    }
+
+    #[test]
+    fn test_pause_switches() {
+        let mut state = get_state();
+
+        state.set_pause(PauseSwitch::marketplace, true);
+        assert_eq!(state.check_not_paused(PauseSwitch::transfers), Ok(()));
+        assert_eq!(state.check_not_paused(PauseSwitch::marketplace), Err(String::from("Canister is paused, try another time")));
+
+        state.pause_reason = Some(String::from("upgrade in progress"));
+        state.set_pause(PauseSwitch::all, true);
+        assert_eq!(state.check_not_paused(PauseSwitch::minting), Err(String::from("Canister is paused, upgrade in progress")));
+        assert!(state.pause_status().transfers);
+    }
+
     #[test]
     fn test_burn() {
         let mut state = get_state();
         let prin = Principal::from_text("tushn-jfas4-lrw4y-d3hun-lyc2x-hr2o2-2spfo-ak45s-jzksj-fzvln-yqe").unwrap();
 
-        let mint_result = state.mint(prin);
+        let mint_result = state.mint(prin, prin);
         let minted_id = mint_result.unwrap();
         
         assert_eq!(minted_id, 1);
@@ -342,6 +814,27 @@ use crate::testing::*;
         assert_eq!(len2, 2);
     }
 
+    #[test]
+    fn test_burned_ids_not_reminted() {
+        let mut state = get_state();
+        state.max_supply = 3;
+
+        assert_eq!(state.mint(user_a(), user_a()), Ok(1));
+        assert_eq!(state.mint(user_a(), user_a()), Ok(2));
+        assert!(state.burn(user_a(), 1).is_ok());
+
+        //Burned id is skipped and still counts towards max supply
+        assert_eq!(state.mint_token_id(user_a(), user_a(), 1), Err(String::from("Could not mint token that is already taken")));
+        assert_eq!(state.check_mintable(2), Err(String::from("Max token count reached")));
+        assert_eq!(state.mint(user_a(), user_a()), Ok(3));
+        assert_eq!(state.mint(user_a(), user_a()), Err(String::from("Max token count reached")));
+
+        //Burned ids are restored from ledger after upgrade
+        state.burned.clear();
+        state.rebuild_burned();
+        assert_eq!(state.burned, vec![1].into_iter().collect());
+    }
+
     #[test]
     fn test_mint_id() {
         let mut state = get_state();
@@ -1,11 +1,19 @@
-use crate::token::State;
-use crate::marketplace::{ Listing, Marketplace };
+use crate::token::{PauseSwitch, State};
+use crate::guards::{admin_guard, marketplace_not_paused};
+use crate::marketplace::{ Listing, ListingPage, ListingQuery, Marketplace, Reservation };
+use crate::marketplace;
+use crate::random;
+use crate::sale::MINT_MEMO_FLAG;
 use ic_cdk_macros::{query, update};
 
-use ic_cdk::{caller};
+use ic_cdk::{caller, trap};
+use ic_cdk::export::candid::Principal;
 
 use common::{ TransactionNotification, TransactionResponse };
 
+/// Maximum number of items in list_many and delist_many
+const MAX_BATCH: usize = 200;
+
 #[query]
 fn get_listed_count() -> u128 {
     let state = Marketplace::get();
@@ -41,21 +49,117 @@ fn listings() -> Vec<Listing> {
     return vals;
 }
 
-#[update]
+//Returns memo that ICP payment for the current listing of token must carry
+#[query]
+fn payment_memo(token_id: u32) -> Result<u64, String> {
+    let state = Marketplace::get();
+    let state = state.borrow();
+    state.listings.get(&token_id).map(|x| x.memo()).ok_or_else(|| String::from("Token is not listed"))
+}
+
+//Returns page of listings filtered by price range and token properties, sorted by query.sort
+#[query]
+fn query_listings(query: ListingQuery) -> ListingPage {
+    Marketplace::get().borrow().query_listings(&query)
+}
+
+#[update(guard="marketplace_not_paused")]
 fn list(token_id: u32, price: u64) -> Result<u64, String> {
     //Only token owner can call this
     State::get().borrow().check_owner(token_id, caller())?;
-    Marketplace::get().borrow_mut().list(caller(), token_id, price)
+    Marketplace::get().borrow_mut().list(caller(), token_id, price, None)
+}
 
+//Lists token with price in ICRC-1 token units, payment_token is the token ledger canister
+#[update(guard="marketplace_not_paused")]
+fn list_with_token(token_id: u32, price: u64, payment_token: Principal) -> Result<u64, String> {
+    //Only token owner can call this
+    State::get().borrow().check_owner(token_id, caller())?;
+    Marketplace::get().borrow_mut().list(caller(), token_id, price, Some(payment_token))
 }
-#[update]
+
+#[update(guard="marketplace_not_paused")]
 async fn delist(token_id: u32) -> Result<u64, String> {
     //Only token owner can call this
     State::get().borrow().check_owner(token_id, caller())?;
     Marketplace::get().borrow_mut().delist(caller(), token_id)
 }
 
+//Lists or reprices several tokens priced in ICP, returns ledger block or error for every item
+#[update(guard="marketplace_not_paused")]
+fn list_many(items: Vec<(u32, u64)>) -> Vec<Result<u64, String>> {
+    if items.len() > MAX_BATCH { trap(&format!("Batch is limited to {} items", MAX_BATCH)); }
+
+    Marketplace::get().borrow_mut().list_many(caller(), &items)
+}
+
+//Removes several tokens from listing, returns ledger block or error for every item
+#[update(guard="marketplace_not_paused")]
+fn delist_many(token_ids: Vec<u32>) -> Vec<Result<u64, String>> {
+    if token_ids.len() > MAX_BATCH { trap(&format!("Batch is limited to {} items", MAX_BATCH)); }
+
+    Marketplace::get().borrow_mut().delist_many(caller(), &token_ids)
+}
+
+//Locks ICP listing for caller for a short time, payment must carry the returned memo and come from caller, buyer can hold few reservations at once and cannot renew an expired one right away
+#[update(guard="marketplace_not_paused")]
+fn reserve(token_id: u32) -> Result<Reservation, String> {
+    Marketplace::get().borrow_mut().reserve(caller(), token_id)
+}
+
+#[query]
+fn reservation(token_id: u32) -> Option<Reservation> {
+    Marketplace::get().borrow().active_reservation(token_id).cloned()
+}
+
 #[update]
 async fn transaction_notification(args: TransactionNotification) -> Result<TransactionResponse, String> {
+    //Caller is checked before any await, so others cannot consume randomness or interleave with notifications
+    Marketplace::get().borrow().check_notifier(caller())?;
+
+    //Paused canister returns error instead of rejecting the call, so the ledger proxy refunds the payment
+    let switch = if args.memo & MINT_MEMO_FLAG != 0 { PauseSwitch::minting } else { PauseSwitch::marketplace };
+    State::get().borrow().check_not_paused(switch)?;
+
+    //Fair mint draws use randomness fetched after payment was made, buyer cannot predict the token id
+    if args.memo & MINT_MEMO_FLAG != 0 && State::get().borrow().fair_mint {
+        random::refresh_mint_seed().await?;
+    }
+
     Marketplace::get().borrow_mut().purchase(caller(), &args)
-}
\ No newline at end of file
+}
+
+//Returns ICRC-1 token canisters listings can be priced in
+#[query]
+fn payment_tokens() -> Vec<Principal> {
+    Marketplace::get().borrow().payment_tokens.iter().copied().collect()
+}
+
+#[update(guard="admin_guard")]
+fn add_payment_token(payment_token: Principal) -> bool {
+    Marketplace::get().borrow_mut().payment_tokens.insert(payment_token)
+}
+
+//Existing listings in removed token cannot be purchased until they are relisted
+#[update(guard="admin_guard")]
+fn remove_payment_token(payment_token: Principal) -> bool {
+    Marketplace::get().borrow_mut().payment_tokens.remove(&payment_token)
+}
+
+//Purchases listing priced in ICRC-1 token, caller must approve this canister for price plus ledger fee with icrc2_approve
+#[update(guard="marketplace_not_paused")]
+async fn purchase_with_token(token_id: u32) -> Result<u64, String> {
+    marketplace::purchase_with_token(caller(), token_id).await
+}
+
+//Returns amount of payment_token owed to owner from ICRC-1 settlements
+#[query]
+fn payment_balance(payment_token: Principal, owner: Principal) -> u64 {
+    Marketplace::get().borrow().balance_of(payment_token, owner)
+}
+
+//Sends caller balance of payment_token to caller
+#[update(guard="marketplace_not_paused")]
+async fn withdraw(payment_token: Principal) -> Result<u64, String> {
+    marketplace::withdraw(caller(), payment_token).await
+}
@@ -0,0 +1,290 @@
+use ic_cdk::api::call::call;
+use ic_cdk::export::candid::parser::types::FuncMode;
+use ic_cdk::export::candid::types::{Function, Serializer, Type};
+use ic_cdk::export::candid::{CandidType, Deserialize, Func, Principal};
+
+use common::account_identifier::AccountIdentifier;
+use common::SendArgs;
+
+/// Candid types of ICP ledger, only fields read by the proxy are declared
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Tokens {
+    pub e8s: u64,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct TimeStamp {
+    pub timestamp_nanos: u64,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct GetBlocksArgs {
+    pub start: u64,
+    pub length: u64,
+}
+
+/// All variants of ledger operation have to be listed, candid rejects unknown variants
+#[derive(Clone, CandidType, Deserialize)]
+pub enum Operation {
+    Mint { to: Vec<u8>, amount: Tokens },
+    Burn { from: Vec<u8>, amount: Tokens },
+    Transfer { from: Vec<u8>, to: Vec<u8>, amount: Tokens },
+    Approve { from: Vec<u8> },
+    TransferFrom { from: Vec<u8>, to: Vec<u8>, amount: Tokens },
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct Transaction {
+    pub memo: u64,
+    pub icrc1_memo: Option<Vec<u8>>,
+    pub operation: Option<Operation>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct CandidBlock {
+    pub transaction: Transaction,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct BlockRange {
+    pub blocks: Vec<CandidBlock>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum QueryArchiveError {
+    BadFirstBlockIndex { requested_index: u64, first_valid_index: u64 },
+    Other { error_code: u64, error_message: String },
+}
+
+/// Query method of archive canister holding a range of blocks
+#[derive(Clone)]
+pub struct QueryArchiveFn(pub Func);
+
+impl CandidType for QueryArchiveFn {
+    //Function type has to match the ledger, func values are checked by mode and signature
+    fn _ty() -> Type {
+        Type::Func(Function {
+            modes: vec![FuncMode::Query],
+            args: vec![GetBlocksArgs::ty()],
+            rets: vec![Result::<BlockRange, QueryArchiveError>::ty()],
+        })
+    }
+    fn idl_serialize<S>(&self, serializer: S) -> Result<(), S::Error>
+    where
+        S: Serializer,
+    {
+        self.0.idl_serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for QueryArchiveFn {
+    fn deserialize<D>(deserializer: D) -> Result<QueryArchiveFn, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        Func::deserialize(deserializer).map(QueryArchiveFn)
+    }
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct ArchivedBlocksRange {
+    pub start: u64,
+    pub length: u64,
+    pub callback: QueryArchiveFn,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct QueryBlocksResponse {
+    pub chain_length: u64,
+    pub blocks: Vec<CandidBlock>,
+    pub first_block_index: u64,
+    pub archived_blocks: Vec<ArchivedBlocksRange>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct TransferArgs {
+    pub memo: u64,
+    pub amount: Tokens,
+    pub fee: Tokens,
+    pub from_subaccount: Option<Vec<u8>>,
+    pub to: Vec<u8>,
+    pub created_at_time: Option<TimeStamp>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum TransferError {
+    BadFee { expected_fee: Tokens },
+    InsufficientFunds { balance: Tokens },
+    TxTooOld { allowed_window_nanos: u64 },
+    TxCreatedInFuture,
+    TxDuplicate { duplicate_of: u64 },
+}
+
+/// Returns block from ledger, follows archive callback for blocks moved to archive canister
+pub async fn get_block(canister: Principal, block_height: u64) -> Result<CandidBlock, String> {
+    let args = GetBlocksArgs { start: block_height, length: 1 };
+
+    let (resp,): (QueryBlocksResponse,) = call(canister, "query_blocks", (args.clone(),))
+        .await
+        .map_err(|(_, s)| format!("Error invoking Ledger Canister, {}", &s))?;
+
+    if block_height >= resp.chain_length {
+        return Err(String::from("Block does not exist yet"));
+    }
+
+    if block_height >= resp.first_block_index {
+        return resp.blocks.into_iter().next().ok_or_else(|| String::from("Block not returned by ledger"));
+    }
+
+    let archive = resp.archived_blocks.into_iter()
+        .find(|x| x.start <= block_height && block_height < x.start + x.length)
+        .ok_or_else(|| String::from("Block not found in ledger archives"))?;
+
+    let callback = archive.callback.0;
+    let (resp,): (Result<BlockRange, QueryArchiveError>,) = call(callback.principal, &callback.method, (args,))
+        .await
+        .map_err(|(_, s)| format!("Error while calling archive canister, {}", &s))?;
+
+    resp.map_err(|e| format!("Archive canister error, {:?}", e))?
+        .blocks.into_iter().next()
+        .ok_or_else(|| String::from("Block not found in archive"))
+}
+
+///Returns sender, recipient, amount and memo of block, returns only send transactions
+pub async fn get_send_transaction(
+    canister: Principal,
+    block_height: u64,
+) -> Result<(AccountIdentifier, AccountIdentifier, u64, u64), String> {
+    let block = get_block(canister, block_height).await?;
+    send_transaction(block.transaction)
+}
+
+/// Returns sender, recipient, amount and memo of send transaction
+pub fn send_transaction(transaction: Transaction) -> Result<(AccountIdentifier, AccountIdentifier, u64, u64), String> {
+    let (from, to, amount) = match transaction
+        .operation
+        .ok_or_else(|| String::from("Transaction transfer is none"))?
+    {
+        Operation::Transfer { from, to, amount } => (
+            AccountIdentifier::from_slice(&from[..]).map_err(|_| String::from("Invalid sender account"))?,
+            AccountIdentifier::from_slice(&to[..]).map_err(|_| String::from("Invalid recipient account"))?,
+            amount.e8s,
+        ),
+        Operation::Burn { .. } => {
+            return Err(String::from(
+                "Notification failed transfer must be of type send, found burn",
+            ))
+        }
+        Operation::Mint { .. } => {
+            return Err(String::from(
+                "Notification failed transfer must be of type send, found mint",
+            ))
+        }
+        Operation::Approve { .. } => {
+            return Err(String::from(
+                "Notification failed transfer must be of type send, found approve",
+            ))
+        }
+        Operation::TransferFrom { .. } => {
+            return Err(String::from(
+                "Notification failed transfer must be of type send, found transfer from",
+            ))
+        }
+    };
+
+    Ok((from, to, amount, memo_of(transaction.memo, transaction.icrc1_memo)))
+}
+
+/// Payments sent with icrc1_transfer carry memo as big endian bytes and leave the u64 memo 0
+pub fn memo_of(memo: u64, icrc1_memo: Option<Vec<u8>>) -> u64 {
+    match icrc1_memo {
+        Some(bytes) if memo == 0 && !bytes.is_empty() && bytes.len() <= 8 => {
+            bytes.iter().fold(0u64, |memo, byte| (memo << 8) | *byte as u64)
+        }
+        _ => memo,
+    }
+}
+
+/// Sends ICP with candid transfer method, returns block height
+pub async fn call_transfer(canister: Principal, args: &SendArgs) -> Result<u64, String> {
+    let to = AccountIdentifier::from_hex(&args.to)?;
+
+    let transfer_args = TransferArgs {
+        memo: args.memo,
+        amount: Tokens { e8s: args.amount.e8s },
+        fee: Tokens { e8s: args.fee.e8s },
+        from_subaccount: args.from_subaccount.map(|x| x.0.to_vec()),
+        to: to.to_vec(),
+        created_at_time: args.created_at_time.as_ref().map(|x| TimeStamp { timestamp_nanos: x.timestamp_nanos }),
+    };
+
+    let (res,): (Result<u64, TransferError>,) = call(canister, "transfer", (transfer_args,))
+        .await
+        .map_err(|(_, s)| format!("Error invoking Ledger Canister, {}", &s))?;
+
+    transfer_result(res)
+}
+
+/// Returns block of transfer, duplicate of transfer already in ledger is reported with its block
+pub fn transfer_result(res: Result<u64, TransferError>) -> Result<u64, String> {
+    match res {
+        Ok(block) | Err(TransferError::TxDuplicate { duplicate_of: block }) => Ok(block),
+        Err(e) => Err(format!("Transfer failed, {:?}", e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ic_cdk::export::candid::{Decode, Encode};
+
+    fn account(subaccount: u8) -> AccountIdentifier {
+        let owner = Principal::from_text("tushn-jfas4-lrw4y-d3hun-lyc2x-hr2o2-2spfo-ak45s-jzksj-fzvln-yqe").unwrap();
+        AccountIdentifier::new(owner, Some(common::account_identifier::Subaccount([subaccount; 32])))
+    }
+
+    fn transfer_block(memo: u64, icrc1_memo: Option<Vec<u8>>) -> CandidBlock {
+        CandidBlock {
+            transaction: Transaction {
+                memo,
+                icrc1_memo,
+                operation: Some(Operation::Transfer { from: account(1).to_vec(), to: account(2).to_vec(), amount: Tokens { e8s: 100000000 } }),
+            },
+        }
+    }
+
+    #[test]
+    fn test_send_transaction() {
+        //Block goes through candid encoding as returned by query_blocks
+        let bytes = Encode!(&transfer_block(42, None)).unwrap();
+        let block = Decode!(&bytes, CandidBlock).unwrap();
+        assert_eq!(send_transaction(block.transaction), Ok((account(1), account(2), 100000000, 42)));
+
+        let block = transfer_block(0, Some(vec![0x01, 0x00]));
+        assert_eq!(send_transaction(block.transaction).map(|x| x.3), Ok(256));
+
+        let mut block = transfer_block(0, None);
+        block.transaction.operation = Some(Operation::Burn { from: account(1).to_vec(), amount: Tokens { e8s: 1 } });
+        assert!(send_transaction(block.transaction.clone()).is_err());
+
+        block.transaction.operation = Some(Operation::Transfer { from: vec![1; 3], to: account(2).to_vec(), amount: Tokens { e8s: 1 } });
+        assert_eq!(send_transaction(block.transaction), Err(String::from("Invalid sender account")));
+    }
+
+    #[test]
+    fn test_memo_of() {
+        assert_eq!(memo_of(7, None), 7);
+        assert_eq!(memo_of(0, Some(0x1234u64.to_be_bytes().to_vec())), 0x1234);
+        //u64 memo is kept when it is set or icrc1 memo does not fit in it
+        assert_eq!(memo_of(7, Some(vec![1])), 7);
+        assert_eq!(memo_of(0, Some(vec![1; 9])), 0);
+        assert_eq!(memo_of(0, Some(vec![])), 0);
+    }
+
+    #[test]
+    fn test_transfer_duplicate() {
+        assert_eq!(transfer_result(Ok(3)), Ok(3));
+        assert_eq!(transfer_result(Err(TransferError::TxDuplicate { duplicate_of: 5 })), Ok(5));
+        assert!(transfer_result(Err(TransferError::TxCreatedInFuture)).is_err());
+    }
+}
@@ -0,0 +1,192 @@
+#[cfg(test)]
+use crate::testing::time;
+
+#[cfg(not(test))]
+use ic_cdk::api::time;
+use ic_cdk::export::candid::Principal;
+
+use common::{Account, ICPTs, SendArgs};
+
+use crate::ledger_client;
+use crate::state::{TransferStatus, STATE};
+
+/// Memo of fee payouts, "FEES" in ascii
+pub const DISBURSE_MEMO: u64 = 0x46454553;
+
+/// Balance a payout is taken from
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Payee {
+    Market,
+    Creator(Principal),
+    CreatorUnallocated,
+}
+
+/// Sends waiting market and creator fees, balances are reserved before every payout and restored if it fails
+pub async fn disburse() -> Result<Vec<TransferStatus>, String> {
+    let payouts = STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        if state.disbursing { return Err(String::from("Disbursement already in progress")); }
+
+        state.get_ledger_canister()?;
+        let payouts = state.payouts();
+        state.disbursing = true;
+
+        Ok(payouts)
+    })?;
+
+    let mut result = Vec::with_capacity(payouts.len());
+
+    for (payee, to, amount) in payouts {
+        let args = SendArgs {
+            memo: DISBURSE_MEMO,
+            amount: ICPTs { e8s: amount - common::TX_FEE },
+            fee: ICPTs { e8s: common::TX_FEE },
+            from_subaccount: None,
+            to: common::account_id(to, None),
+            created_at_time: None,
+            to_account: Some(Account::from(to)),
+        };
+
+        //Balance is taken before the call, so nothing is left to subtract after it
+        STATE.with(|s| s.borrow_mut().reserve_payout(payee, amount));
+
+        let sent = ledger_client::send(&args).await;
+
+        //State is borrowed again after every call, notifications can be processed in the meantime
+        let payment = STATE.with(|s| {
+            let mut state = s.borrow_mut();
+            if sent.is_err() { state.release_payout(payee, amount); }
+
+            state.record_payment(args, sent)
+        });
+
+        result.push(payment);
+    }
+
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        state.disbursing = false;
+        state.last_disbursement = time();
+    });
+
+    Ok(result)
+}
+
+impl crate::state::State {
+    /// Returns payouts of waiting fees above transfer fee to configured accounts
+    fn payouts(&self) -> Vec<(Payee, Principal, u64)> {
+        let mut payouts = Vec::new();
+
+        if let Some(market_address) = self.market_address {
+            payouts.push((Payee::Market, market_address, self.waiting_market_fee));
+        }
+
+        let mut allocated = 0;
+        for (account, balance) in self.creator_balances.iter() {
+            allocated += balance;
+            payouts.push((Payee::Creator(*account), *account, *balance));
+        }
+
+        //Creators fee not split between royalty recipients goes to creator_address
+        if let Some(creator_address) = self.creator_address {
+            payouts.push((Payee::CreatorUnallocated, creator_address, self.waiting_creator_fee.saturating_sub(allocated)));
+        }
+
+        payouts.retain(|(_, _, amount)| *amount > common::TX_FEE);
+        payouts
+    }
+
+    /// Takes payout amount from waiting fees and creator balance
+    fn reserve_payout(&mut self, payee: Payee, amount: u64) {
+        match payee {
+            Payee::Market => self.waiting_market_fee = self.waiting_market_fee.saturating_sub(amount),
+            Payee::Creator(account) => {
+                self.waiting_creator_fee = self.waiting_creator_fee.saturating_sub(amount);
+                let balance = self.creator_balances.entry(account).or_default();
+                *balance = balance.saturating_sub(amount);
+                if *balance == 0 { self.creator_balances.remove(&account); }
+            }
+            Payee::CreatorUnallocated => self.waiting_creator_fee = self.waiting_creator_fee.saturating_sub(amount),
+        }
+    }
+
+    /// Returns amount of failed payout to waiting fees and creator balance
+    fn release_payout(&mut self, payee: Payee, amount: u64) {
+        match payee {
+            Payee::Market => self.waiting_market_fee += amount,
+            Payee::Creator(account) => {
+                self.waiting_creator_fee += amount;
+                *self.creator_balances.entry(account).or_default() += amount;
+            }
+            Payee::CreatorUnallocated => self.waiting_creator_fee += amount,
+        }
+    }
+
+    /// Returns true if heartbeat should start disbursement
+    pub fn disbursement_due(&self, now: u64) -> bool {
+        self.disburse_interval > 0 && !self.disbursing && now >= self.last_disbursement + self.disburse_interval
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::State;
+
+    fn principal(text: &str) -> Principal {
+        Principal::from_text(text).unwrap()
+    }
+
+    #[test]
+    fn test_payouts() {
+        let mut state = State::default();
+        let market = principal("rrkah-fqaaa-aaaaa-aaaaq-cai");
+        let creator = principal("ryjl3-tyaaa-aaaaa-aaaba-cai");
+        let recipient = principal("tushn-jfas4-lrw4y-d3hun-lyc2x-hr2o2-2spfo-ak45s-jzksj-fzvln-yqe");
+
+        state.waiting_market_fee = 500000;
+        state.waiting_creator_fee = 300000;
+        state.creator_balances.insert(recipient, 200000);
+
+        //Fees without configured account stay waiting
+        assert_eq!(state.payouts(), vec![(Payee::Creator(recipient), recipient, 200000)]);
+
+        state.market_address = Some(market);
+        state.creator_address = Some(creator);
+        assert_eq!(state.payouts(), vec![
+            (Payee::Market, market, 500000),
+            (Payee::Creator(recipient), recipient, 200000),
+            (Payee::CreatorUnallocated, creator, 100000),
+        ]);
+
+        //Payouts not covering transfer fee are skipped
+        state.waiting_creator_fee = 200000 + common::TX_FEE;
+        assert_eq!(state.payouts().len(), 2);
+    }
+
+    #[test]
+    fn test_payout_bookkeeping() {
+        let mut state = State::default();
+        let recipient = principal("tushn-jfas4-lrw4y-d3hun-lyc2x-hr2o2-2spfo-ak45s-jzksj-fzvln-yqe");
+
+        state.waiting_market_fee = 500000;
+        state.waiting_creator_fee = 300000;
+        state.creator_balances.insert(recipient, 200000);
+
+        state.reserve_payout(Payee::Creator(recipient), 200000);
+        assert_eq!(state.waiting_creator_fee, 100000);
+        assert!(state.creator_balances.is_empty());
+
+        //Failed payout is returned to balances
+        state.release_payout(Payee::Creator(recipient), 200000);
+        assert_eq!(state.waiting_creator_fee, 300000);
+        assert_eq!(state.creator_balances.get(&recipient), Some(&200000));
+
+        //Balances changed during the call cannot underflow
+        state.waiting_market_fee = 100000;
+        state.reserve_payout(Payee::Market, 500000);
+        assert_eq!(state.waiting_market_fee, 0);
+        state.reserve_payout(Payee::CreatorUnallocated, 500000);
+        assert_eq!(state.waiting_creator_fee, 0);
+    }
+}
@@ -32,6 +32,10 @@ impl IntSet {
     pub fn insert(&mut self, key: u64, value: ()) -> bool {
         self.0.insert(key, value)
     }
+
+    pub fn remove(&mut self, key: u64) -> Option<()> {
+        self.0.remove(key)
+    }
 }
 
 impl Default for IntSet {
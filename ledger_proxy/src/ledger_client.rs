@@ -0,0 +1,198 @@
+use ic_cdk::api::call::call_raw;
+use ic_cdk::export::candid::{CandidType, Deserialize, Nat, Principal};
+use serde::Serialize;
+use std::convert::TryFrom;
+
+use prost::Message;
+
+use common::account_identifier::AccountIdentifier;
+use common::{call_icrc1_transfer, call_send_dfx, SendArgs, TransferArg};
+
+use crate::candid_ledger;
+use crate::ledger;
+use crate::state::STATE;
+
+/// Ledger interface used for reading payment blocks and sending ICP
+#[derive(Clone, Copy, Debug, Default, PartialEq, CandidType, Deserialize, Serialize)]
+#[allow(non_camel_case_types)]
+pub enum LedgerApi {
+    /// Protobuf block_pb and send_dfx
+    #[default]
+    protobuf,
+    /// Candid query_blocks and transfer
+    candid,
+    /// Candid query_blocks and ICRC-1 icrc1_transfer
+    icrc1,
+}
+
+fn ledger_config() -> Result<(Principal, LedgerApi), String> {
+    STATE.with(|s| {
+        let state = s.borrow();
+        Ok((state.get_ledger_canister()?, state.ledger_api))
+    })
+}
+
+///Returns sender, recipient, amount and memo of send transaction in block
+pub async fn get_send_transaction(block_height: u64) -> Result<(AccountIdentifier, AccountIdentifier, u64, u64), String> {
+    let (ledger_canister, api) = ledger_config()?;
+
+    match api {
+        LedgerApi::protobuf => get_pb_send_transaction(ledger_canister, block_height).await,
+        LedgerApi::candid | LedgerApi::icrc1 => candid_ledger::get_send_transaction(ledger_canister, block_height).await,
+    }
+}
+
+/// Sends ICP with configured ledger interface, returns block height
+pub async fn send(args: &SendArgs) -> Result<u64, String> {
+    let (ledger_canister, api) = ledger_config()?;
+
+    match icrc1_transfer_arg(api, args) {
+        Some(transfer_args) => call_icrc1_transfer(ledger_canister, &transfer_args).await,
+        None if api == LedgerApi::protobuf => call_send_dfx(ledger_canister, args).await,
+        None => candid_ledger::call_transfer(ledger_canister, args).await,
+    }
+}
+
+/// Returns icrc1_transfer args when transfer is sent with ICRC-1, transfers to account id without known owner fall back to candid transfer
+fn icrc1_transfer_arg(api: LedgerApi, args: &SendArgs) -> Option<TransferArg> {
+    let to = args.to_account.as_ref().filter(|_| api == LedgerApi::icrc1)?;
+
+    Some(TransferArg {
+        from_subaccount: args.from_subaccount.map(|x| x.0.to_vec()),
+        to: to.clone(),
+        amount: Nat::from(args.amount.e8s),
+        fee: Some(Nat::from(args.fee.e8s)),
+        //Read back by candid_ledger::memo_of
+        memo: Some(args.memo.to_be_bytes().to_vec()),
+        created_at_time: args.created_at_time.as_ref().map(|x| x.timestamp_nanos),
+    })
+}
+
+///Downloads block with block_pb and returns parsed information, returns only send transactions
+async fn get_pb_send_transaction(
+    ledger_canister: Principal,
+    block_height: u64,
+) -> Result<(AccountIdentifier, AccountIdentifier, u64, u64), String> {
+    //Get block from ledger
+    let raw_block: ledger::EncodedBlock = get_block(ledger_canister, block_height).await?;
+    let block_data = ledger::Block::decode(&raw_block.block[..])
+        .map_err(|x| format!("Could not decode block, {}", x))?;
+    let transaction = block_data
+        .transaction
+        .ok_or_else(|| String::from("Transaction is None"))?;
+
+    let (_from, _to, amount) = match transaction
+        .transfer
+        .ok_or_else(|| String::from("Transaction transfer is none"))?
+    {
+        ledger::transaction::Transfer::Send(item) => (
+            AccountIdentifier::from_slice(&item.from.unwrap().hash[..]).unwrap(),
+            AccountIdentifier::from_slice(&item.to.unwrap().hash[..]).unwrap(),
+            item.amount
+                .ok_or_else(|| String::from("Amount is empty!"))?
+                .e8s,
+        ),
+        ledger::transaction::Transfer::Burn(_) => {
+            return Err(String::from(
+                "Notification failed transfer must be of type send, found burn",
+            ))
+        }
+        ledger::transaction::Transfer::Mint(_) => {
+            return Err(String::from(
+                "Notification failed transfer must be of type send, found mint",
+            ))
+        }
+    };
+
+    Ok((
+        _from,
+        _to,
+        amount,
+        transaction.memo.map_or_else(|| 0, |s| s.memo),
+    ))
+}
+
+async fn get_block(canister: Principal, block_height: u64) -> Result<ledger::EncodedBlock, String> {
+    let req = ledger::BlockRequest { block_height };
+    let mut buf = Vec::with_capacity(req.encoded_len());
+    req.encode(&mut buf).unwrap();
+
+    let res = call_raw(canister, "block_pb", buf, 0)
+        .await
+        .map_err(|(_, text)| text)?;
+
+    let resp = ledger::BlockResponse::decode(&res[..])
+        .map_err(|x| format!("Prost decoding error {}", x))?;
+    match resp.block_content.ok_or_else(|| String::from("Block response is empty"))? {
+        ledger::block_response::BlockContent::Block(raw_block) => Ok(raw_block),
+        //Older blocks are moved to archive canister, ledger returns its id
+        ledger::block_response::BlockContent::CanisterId(archive) => {
+            let archive = Principal::try_from(&archive.serialized_id[..])
+                .map_err(|x| format!("Invalid archive canister id, {}", x))?;
+            get_archived_block(archive, block_height).await
+        }
+    }
+}
+
+async fn get_archived_block(archive: Principal, block_height: u64) -> Result<ledger::EncodedBlock, String> {
+    let req = ledger::BlockHeight { height: block_height };
+    let mut buf = Vec::with_capacity(req.encoded_len());
+    req.encode(&mut buf).unwrap();
+
+    let res = call_raw(archive, "get_block_pb", buf, 0)
+        .await
+        .map_err(|(_, text)| format!("Error while calling archive canister, {}", text))?;
+
+    let resp = ledger::BlockResponse::decode(&res[..])
+        .map_err(|x| format!("Prost decoding error {}", x))?;
+    match resp.block_content.ok_or_else(|| String::from("Block not found in archive"))? {
+        ledger::block_response::BlockContent::Block(raw_block) => Ok(raw_block),
+        ledger::block_response::BlockContent::CanisterId(_prin) => {
+            Err(String::from("Archive canister does not hold the block"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::{Account, ICPTs, Subaccount, TimeStamp};
+
+    fn send_args(to_account: Option<Account>) -> SendArgs {
+        SendArgs {
+            memo: 0x0102,
+            amount: ICPTs { e8s: 100000000 },
+            fee: ICPTs { e8s: common::TX_FEE },
+            from_subaccount: Some(Subaccount([3; 32])),
+            to: String::default(),
+            created_at_time: Some(TimeStamp { timestamp_nanos: 5 }),
+            to_account,
+        }
+    }
+
+    #[test]
+    fn test_icrc1_transfer_arg() {
+        let owner = Principal::from_text("tushn-jfas4-lrw4y-d3hun-lyc2x-hr2o2-2spfo-ak45s-jzksj-fzvln-yqe").unwrap();
+        let to = Account { owner, subaccount: Some(vec![4; 32]) };
+
+        assert!(icrc1_transfer_arg(LedgerApi::protobuf, &send_args(Some(to.clone()))).is_none());
+        assert!(icrc1_transfer_arg(LedgerApi::candid, &send_args(Some(to.clone()))).is_none());
+        //Account id without owner is sent with candid transfer
+        assert!(icrc1_transfer_arg(LedgerApi::icrc1, &send_args(None)).is_none());
+
+        let arg = icrc1_transfer_arg(LedgerApi::icrc1, &send_args(Some(to))).unwrap();
+        assert_eq!(arg.to.owner, owner);
+        assert_eq!(arg.to.subaccount, Some(vec![4; 32]));
+        assert_eq!(arg.from_subaccount, Some(vec![3; 32]));
+        assert_eq!(arg.amount, Nat::from(100000000u64));
+        assert_eq!(arg.created_at_time, Some(5));
+
+        //Memo is read back from the block the same way as payments sent with ICRC-1
+        assert_eq!(candid_ledger::memo_of(0, arg.memo), 0x0102);
+    }
+
+    #[test]
+    fn test_default_api() {
+        assert_eq!(LedgerApi::default(), LedgerApi::protobuf);
+    }
+}
@@ -2,9 +2,11 @@ use crate::state::NotificationStatus;
 use crate::state::ProxyStatus;
 use crate::state::TransferStatus;
 use ic_cdk::export::candid::Principal;
+use ic_cdk::api::time;
+use ic_cdk::caller;
 use ic_cdk_macros::*;
 
-use common::SendArgs;
+use common::{SendArgs, Subaccount};
 
 mod ledger {
     include!("../gen/ic_ledger.pb.v1.rs");
@@ -15,12 +17,35 @@ mod int_set;
 mod storage;
 use crate::storage::StableStorage;
 
+#[cfg(test)]
+mod testing;
+
 mod state;
 use state::{State, STATE};
 
+mod guards;
+use guards::owner_guard;
+
+mod disburse;
+
+mod retry;
+use retry::PendingPayout;
+
+mod notify;
+use notify::{BlockNotification, Resolution};
+
+mod subaccount;
+
+mod candid_ledger;
+mod ledger_client;
+use ledger_client::LedgerApi;
+
+//Owner defaults to the installing principal
 #[init]
-fn init() {
+fn init(owner: Option<Principal>) {
     StableStorage::get().borrow_mut().init_storage().unwrap();
+
+    STATE.with(|s| s.borrow_mut().owner = Some(owner.unwrap_or_else(caller)));
 }
 
 #[pre_upgrade]
@@ -33,8 +58,9 @@ fn pre() {
     });
 }
 
+//Owner argument replaces current owner, proxies upgraded from versions without owner get the upgrading principal
 #[post_upgrade]
-fn post() {
+fn post(owner: Option<Principal>) {
     let storage = StableStorage::get();
     let mut st = storage.borrow_mut();
 
@@ -43,7 +69,11 @@ fn post() {
     let state = st.restore_state().unwrap();
 
     STATE.with(|x| {
-        *x.borrow_mut() = state;
+        let mut x = x.borrow_mut();
+        *x = state;
+        if owner.is_some() || x.owner.is_none() {
+            x.owner = Some(owner.unwrap_or_else(caller));
+        }
     });
 
     // let (st, ) : (State, ) = ic_cdk::storage::stable_restore().unwrap();
@@ -54,7 +84,30 @@ fn post() {
     // })
 }
 
+#[query]
+fn get_owner() -> Option<Principal> {
+    STATE.with(|s| s.borrow().owner)
+}
+
+#[query]
+fn get_pending_owner() -> Option<(Principal, u64)> {
+    STATE.with(|s| s.borrow().pending_owner)
+}
+
+//Ownership moves when proposed owner calls accept_ownership before the proposal expires
+#[update(guard = "owner_guard")]
+fn propose_owner(owner: Principal) -> bool {
+    STATE.with(|s| s.borrow_mut().propose_owner(owner, time()));
+
+    true
+}
+
 #[update]
+fn accept_ownership() -> Result<(), String> {
+    STATE.with(|s| s.borrow_mut().accept_ownership(caller(), time()))
+}
+
+#[update(guard = "owner_guard")]
 fn set_ledger_canister(id: Principal) -> bool {
     STATE.with(|s| {
         s.borrow_mut().ledger_canister = Some(id);
@@ -63,7 +116,22 @@ fn set_ledger_canister(id: Principal) -> bool {
     true
 }
 
-#[update]
+#[query]
+fn get_ledger_api() -> LedgerApi {
+    STATE.with(|s| s.borrow().ledger_api)
+}
+
+//Selects ledger interface for reading payments and sending ICP, ICRC-1 is used for payouts to known principals
+#[update(guard = "owner_guard")]
+fn set_ledger_api(api: LedgerApi) -> bool {
+    STATE.with(|s| {
+        s.borrow_mut().ledger_api = api;
+    });
+
+    true
+}
+
+#[update(guard = "owner_guard")]
 fn set_token_canister(id: Principal) -> bool {
     STATE.with(|s| {
         s.borrow_mut().token_canister = Some(id);
@@ -72,6 +140,71 @@ fn set_token_canister(id: Principal) -> bool {
     true
 }
 
+#[update(guard = "owner_guard")]
+fn set_market_address(address: Principal) -> bool {
+    STATE.with(|s| s.borrow_mut().market_address = Some(address));
+
+    true
+}
+
+#[update(guard = "owner_guard")]
+fn set_creator_address(address: Principal) -> bool {
+    STATE.with(|s| s.borrow_mut().creator_address = Some(address));
+
+    true
+}
+
+//Interval of automatic disbursement in nanoseconds, 0 disables it
+#[update(guard = "owner_guard")]
+fn set_disburse_interval(interval: u64) -> bool {
+    STATE.with(|s| s.borrow_mut().disburse_interval = interval);
+
+    true
+}
+
+#[query]
+fn get_fee_accounts() -> (Option<Principal>, Option<Principal>) {
+    STATE.with(|s| (s.borrow().market_address, s.borrow().creator_address))
+}
+
+//Sends waiting market and creator fees to their accounts, returns recorded payouts
+#[update(guard = "owner_guard")]
+async fn disburse() -> Result<Vec<TransferStatus>, String> {
+    disburse::disburse().await
+}
+
+#[query]
+fn get_pending_payouts() -> Vec<PendingPayout> {
+    STATE.with(|s| s.borrow().pending_payouts.clone())
+}
+
+//Sends queued payout now, failed payouts have to be reissued first
+#[update(guard = "owner_guard")]
+async fn retry_payout(id: u64) -> Result<u64, String> {
+    retry::retry_payout(id).await
+}
+
+//Queues failed payout again with new dedup window, call only after checking the ledger that it was not paid
+#[update(guard = "owner_guard")]
+fn reissue_payout(id: u64) -> Result<PendingPayout, String> {
+    STATE.with(|s| s.borrow_mut().reissue_payout(id, time()))
+}
+
+//Drops payout from retry queue, for payouts settled manually
+#[update(guard = "owner_guard")]
+fn resolve_payout(id: u64) -> Result<PendingPayout, String> {
+    STATE.with(|s| s.borrow_mut().resolve_payout(id))
+}
+
+#[heartbeat]
+async fn heartbeat() {
+    retry::retry_due_payouts().await;
+
+    if STATE.with(|s| s.borrow().disbursement_due(time())) {
+        let _ = disburse::disburse().await;
+    }
+}
+
 #[query]
 fn get_blocks() -> Vec<SendArgs> {
     STATE.with(|s| s.borrow().blocks.clone())
@@ -98,8 +231,8 @@ fn get_notifications() -> Vec<NotificationStatus> {
 }
 
 #[query]
-fn get_market_fee() -> u64 {
-    STATE.with(|s| s.borrow().market_fee)
+fn get_creator_balances() -> Vec<(Principal, u64)> {
+    STATE.with(|s| s.borrow().creator_balances.iter().map(|(k, v)| (*k, *v)).collect())
 }
 
 #[query]
@@ -112,11 +245,52 @@ fn get_status() -> ProxyStatus {
     })
 }
 
-//Notifies canister about transaction
+//Notifies canister about transaction, unfinished block is resumed from its last stage
+//Payment sent from a subaccount of caller is accepted when that subaccount is given
 #[update]
-async fn notify(block_height: u64) -> Result<(), String> {
-    let state = STATE.with(|s| s.clone());
-    let mut state = state.borrow_mut();
+async fn notify(block_height: u64, from_subaccount: Option<Subaccount>) -> Result<(), String> {
+    notify::notify(caller(), block_height, from_subaccount).await
+}
+
+//Account id buyers can pay into instead of the default account of this canister
+#[query]
+fn buyer_deposit_account(buyer: Principal) -> String {
+    subaccount::account_of(ic_cdk::id(), Some(subaccount::buyer_subaccount(buyer))).to_hex()
+}
+
+#[query]
+fn token_deposit_account(token_id: u32) -> String {
+    subaccount::account_of(ic_cdk::id(), Some(subaccount::token_subaccount(token_id))).to_hex()
+}
+
+#[query]
+fn get_block_notification(block_height: u64) -> Option<BlockNotification> {
+    STATE.with(|s| s.borrow().block_notifications.get(&block_height).cloned())
+}
+
+#[query]
+fn get_unfinished_notifications() -> Vec<BlockNotification> {
+    STATE.with(|s| {
+        s.borrow().block_notifications.values()
+            .filter(|x| !x.is_final())
+            .cloned()
+            .collect()
+    })
+}
+
+//Unlocks block left in flight by a notify call that trapped, call only when no notify of the block is running
+#[update(guard = "owner_guard")]
+fn release_notification(block_height: u64) -> bool {
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        let in_flight = state.block_notifications.get(&block_height).is_some_and(|x| x.in_flight);
+        state.end_notification(block_height);
+        in_flight
+    })
+}
 
-    state.notify(block_height).await
+//Refunds failed block or pays it out from given or stored token canister response, check the token canister first
+#[update(guard = "owner_guard")]
+async fn resolve_notification(block_height: u64, resolution: Resolution) -> Result<(), String> {
+    notify::resolve(block_height, resolution).await
 }
@@ -0,0 +1,685 @@
+use ic_cdk::api::call::call_raw;
+use ic_cdk::export::candid::{CandidType, Decode, Deserialize, Principal};
+use serde::Serialize;
+
+#[cfg(test)]
+use crate::testing::{id, time};
+
+#[cfg(not(test))]
+use ic_cdk::api::time;
+#[cfg(not(test))]
+use ic_cdk::id;
+
+use common::account_identifier::AccountIdentifier;
+use common::{Account, ICPTs, SendArgs, Subaccount, TimeStamp, TransactionNotification, TransactionResponse};
+
+use crate::ledger_client;
+use crate::subaccount::{account_of, deposit_subaccount};
+use crate::state::{AppErr, ErrType, NotificationStatus, State, STATE};
+
+/// Steps of block processing, every step is stored before the next await
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize, Serialize)]
+#[allow(non_camel_case_types)]
+pub enum NotifyStage {
+    /// Block claimed by caller, not read from ledger yet
+    received,
+    /// Block is a send to this canister, sender, amount and memo are known
+    verified,
+    /// Token canister accepted payment, response is stored
+    token_notified,
+    /// Seller and surplus payouts issued, failed ones are in the retry queue
+    paid,
+    /// Payment returned to sender
+    refunded,
+    /// Block cannot be processed and was not refunded, owner can refund or pay it out with resolve_notification
+    failed,
+}
+
+#[derive(Clone, CandidType, Deserialize, Serialize)]
+pub struct BlockNotification {
+    pub block: u64,
+    pub caller: Principal,
+    pub stage: NotifyStage,
+    pub updated_at: u64,
+    /// Sender account id in hex
+    pub from: Option<String>,
+    /// Subaccount of caller the payment was sent from, given by caller and checked against sender
+    #[serde(default)]
+    pub from_subaccount: Option<Subaccount>,
+    /// Deposit subaccount of this canister that received the payment
+    #[serde(default)]
+    pub to_subaccount: Option<Subaccount>,
+    pub amount: u64,
+    pub memo: u64,
+    pub response: Option<TransactionResponse>,
+    /// Reason of refund or failure, or last error of resumable stage
+    pub error: Option<String>,
+    /// Set while a notify call processes the block, it is stored before the first await so other calls cannot resume the block
+    #[serde(default)]
+    pub in_flight: bool,
+}
+
+/// Owner decision on failed block, pay uses given response or the stored one when none is given
+#[derive(Clone, CandidType, Deserialize)]
+#[allow(non_camel_case_types)]
+pub enum Resolution {
+    refund,
+    pay(Option<TransactionResponse>),
+}
+
+impl BlockNotification {
+    pub fn is_final(&self) -> bool {
+        matches!(self.stage, NotifyStage::paid | NotifyStage::refunded | NotifyStage::failed)
+    }
+}
+
+impl State {
+    /// Claims block for caller or returns stage of unfinished block to resume, block stays locked until end_notification
+    pub fn begin_notification(&mut self, caller: Principal, block: u64, from_subaccount: Option<Subaccount>, now: u64) -> Result<NotifyStage, String> {
+        let owner = self.owner;
+        if let Some(notification) = self.block_notifications.get_mut(&block) {
+            if notification.is_final() { return Err(String::from("Block already processed")); }
+            if notification.caller != caller && owner != Some(caller) { return Err(String::from("Block is processed for another caller")); }
+            if notification.in_flight { return Err(String::from("Block is being processed")); }
+
+            notification.in_flight = true;
+            notification.updated_at = now;
+            return Ok(notification.stage.clone());
+        }
+
+        //Blocks processed before notifications were tracked
+        self.is_processed(block)?;
+        self.token_canister.ok_or_else(|| String::from("Token canister not set!"))?;
+
+        self.blocks_processed.insert(block, ());
+        self.block_notifications.insert(block, BlockNotification {
+            block,
+            caller,
+            stage: NotifyStage::received,
+            updated_at: now,
+            from: None,
+            from_subaccount,
+            to_subaccount: None,
+            amount: 0,
+            memo: 0,
+            response: None,
+            error: None,
+            in_flight: true,
+        });
+
+        Ok(NotifyStage::received)
+    }
+
+    /// Releases claim of block, it can be notified again by any caller
+    fn drop_notification(&mut self, block: u64) {
+        self.block_notifications.remove(&block);
+        self.blocks_processed.remove(block);
+    }
+
+    /// Unlocks block after notify call returned, unfinished block can be resumed afterwards
+    pub fn end_notification(&mut self, block: u64) {
+        if let Some(notification) = self.block_notifications.get_mut(&block) {
+            notification.in_flight = false;
+        }
+    }
+
+    /// Stores block read from ledger, block must be a send to this canister or one of its deposit subaccounts
+    fn verify_transaction(&mut self, block: u64, transaction: Result<(AccountIdentifier, AccountIdentifier, u64, u64), String>, canister: Principal, now: u64) -> Result<NotifyStage, String> {
+        let (from, to, amount, memo) = match transaction {
+            Ok(x) => x,
+            Err(e) => {
+                //Ledger errors leave block in received stage, notify can be called again
+                self.update_notification(block, now, |x| x.error = Some(e.clone()))?;
+                return Err(e);
+            }
+        };
+
+        //Claim is bound to the block sender, a wrong claim is dropped so it cannot block the buyer or trigger a refund
+        let (caller, from_subaccount) = self.block_notifications.get(&block).map(|x| (x.caller, x.from_subaccount)).ok_or_else(|| String::from("Block notification not found"))?;
+        if account_of(caller, from_subaccount) != from {
+            self.drop_notification(block);
+            return Err(String::from("Invalid block! Caller does not match block sender"));
+        }
+
+        //Check if receiving account is current canister or one of its deposit subaccounts
+        let to_subaccount = match deposit_subaccount(canister, to, caller, memo) {
+            Ok(x) => x,
+            Err(error) => {
+                self.drop_notification(block);
+                return Err(error);
+            }
+        };
+
+        let notification = self.update_notification(block, now, |x| {
+            x.stage = NotifyStage::verified;
+            x.from = Some(from.to_hex());
+            x.to_subaccount = to_subaccount;
+            x.amount = amount;
+            x.memo = memo;
+            x.error = None;
+        })?;
+
+        Ok(notification.stage)
+    }
+
+    /// Moves block to paid and returns seller, surplus and fee transfers, fees are stored before any transfer
+    fn begin_payment(&mut self, block: u64, canister: Principal, now: u64) -> Result<Vec<SendArgs>, String> {
+        let notification = self.block_notifications.get(&block).cloned().ok_or_else(|| String::from("Block notification not found"))?;
+        if notification.stage != NotifyStage::token_notified { return Err(String::from("Token canister did not accept payment of block")); }
+        let resp = notification.response.ok_or_else(|| String::from("Token canister response not stored"))?;
+
+        let seller = match self.apply_fees(notification.amount, &resp) {
+            Ok(seller) => seller,
+            Err(e) => {
+                self.update_notification(block, now, |x| {
+                    x.stage = NotifyStage::failed;
+                    x.error = Some(e.clone());
+                })?;
+                return Err(e);
+            }
+        };
+
+        //Stage moves to paid before any transfer, resumed notify cannot pay twice
+        self.update_notification(block, now, |x| x.stage = NotifyStage::paid)?;
+
+        //Payouts are sent from the deposit subaccount that received the payment
+        let deposit = notification.to_subaccount;
+        let amounts = PayoutAmounts::new(notification.amount, resp.price, seller, deposit.is_some());
+
+        let mut payouts = Vec::new();
+        if amounts.seller > 0 {
+            payouts.push(send_args(deposit, common::account_id(resp.seller, None), Some(Account::from(resp.seller)), amounts.seller, 0));
+        }
+        if amounts.refund > 0 {
+            //Sender was verified to be the caller account before notifying token canister
+            let buyer = Account { owner: notification.caller, subaccount: notification.from_subaccount.map(|x| x.0.to_vec()) };
+            payouts.push(send_args(deposit, notification.from.unwrap_or_default(), Some(buyer), amounts.refund, notification.memo));
+        }
+        if amounts.sweep > 0 {
+            //Fees are moved to default account so disbursement can pay them
+            payouts.push(send_args(deposit, common::account_id(canister, None), Some(Account::from(canister)), amounts.sweep, notification.memo));
+        }
+
+        Ok(payouts)
+    }
+
+    /// Moves block to refunded and returns transfer back to sender, block accepted by token canister is never refunded
+    fn begin_refund(&mut self, block: u64, reason: String, now: u64) -> Result<Option<SendArgs>, String> {
+        let stage = self.block_notifications.get(&block).map(|x| x.stage.clone()).ok_or_else(|| String::from("Block notification not found"))?;
+        if !matches!(stage, NotifyStage::received | NotifyStage::verified) { return Err(String::from("Block cannot be refunded in its stage")); }
+
+        let notification = self.update_notification(block, now, |x| {
+            x.stage = NotifyStage::refunded;
+            x.error = Some(reason.clone());
+        })?;
+
+        Ok(refund_args(&notification))
+    }
+
+    /// Moves failed block to refunded or paid as decided by owner and returns transfers to make
+    pub fn begin_resolution(&mut self, block: u64, resolution: Resolution, canister: Principal, now: u64) -> Result<Vec<SendArgs>, String> {
+        let notification = self.block_notifications.get(&block).ok_or_else(|| String::from("Block notification not found"))?;
+        if notification.stage != NotifyStage::failed { return Err(String::from("Only failed blocks can be resolved")); }
+        if notification.in_flight { return Err(String::from("Block is being processed")); }
+
+        match resolution {
+            Resolution::refund => {
+                let notification = self.update_notification(block, now, |x| x.stage = NotifyStage::refunded)?;
+                Ok(refund_args(&notification).into_iter().collect())
+            }
+            Resolution::pay(response) => {
+                let response = response.or_else(|| notification.response.clone()).ok_or_else(|| String::from("Token canister response not stored"))?;
+                self.update_notification(block, now, |x| {
+                    x.stage = NotifyStage::token_notified;
+                    x.response = Some(response);
+                })?;
+                self.begin_payment(block, canister, now)
+            }
+        }
+    }
+
+    /// Updates stored notification of block
+    fn update_notification<F: FnOnce(&mut BlockNotification)>(&mut self, block: u64, now: u64, f: F) -> Result<BlockNotification, String> {
+        let notification = self.block_notifications.get_mut(&block).ok_or_else(|| String::from("Block notification not found"))?;
+        f(notification);
+        notification.updated_at = now;
+
+        Ok(notification.clone())
+    }
+}
+
+/// Returns transfer of received payment back to sender, None when it does not cover TX_FEE
+fn refund_args(notification: &BlockNotification) -> Option<SendArgs> {
+    //Owner of sender account is known only when caller sent the payment
+    let from = notification.from.clone().unwrap_or_default();
+    let owner = Some(Account { owner: notification.caller, subaccount: notification.from_subaccount.map(|x| x.0.to_vec()) })
+        .filter(|_| account_of(notification.caller, notification.from_subaccount).to_hex() == from);
+
+    match notification.amount > common::TX_FEE {
+        true => Some(send_args(notification.to_subaccount, from, owner, notification.amount - common::TX_FEE, notification.memo)),
+        false => None,
+    }
+}
+
+/// Amounts sent out of received payment, 0 means the transfer is not made. Every transfer costs TX_FEE on top of its amount.
+/// Seller pays fee of its payout and of moving fees out of a deposit subaccount, buyer pays fee of surplus refund,
+/// so market and creator fees reach the default account in full and transfers from a deposit spend exactly its balance
+#[derive(Debug, PartialEq)]
+struct PayoutAmounts {
+    seller: u64,
+    refund: u64,
+    sweep: u64,
+}
+
+impl PayoutAmounts {
+    fn new(amount: u64, price: u64, seller: u64, deposit: bool) -> PayoutAmounts {
+        let fees = price - seller;
+        let surplus = amount - price;
+
+        //Surplus not covering refund fee cannot be returned, it is moved with the fees
+        let refund = surplus.saturating_sub(common::TX_FEE);
+        let dust = if refund > 0 { 0 } else { surplus };
+
+        let (seller, sweep) = match deposit && fees + dust > 0 {
+            //Share too small to pay its fee stays in the default account
+            false => (seller.saturating_sub(common::TX_FEE), 0),
+            true if seller > 2 * common::TX_FEE => (seller - 2 * common::TX_FEE, fees + dust),
+            //Seller share too small to pay both fees is moved with the fees and pays fee of the move
+            true => (0, (fees + dust + seller).saturating_sub(common::TX_FEE)),
+        };
+
+        PayoutAmounts { seller, refund, sweep }
+    }
+}
+
+/// Processes block sent to this canister, resumes from the stored stage when called again
+pub async fn notify(caller: Principal, block: u64, from_subaccount: Option<Subaccount>) -> Result<(), String> {
+    let stage = STATE.with(|s| s.borrow_mut().begin_notification(caller, block, from_subaccount, time()))?;
+
+    let result = process(block, stage).await;
+
+    STATE.with(|s| s.borrow_mut().end_notification(block));
+    result
+}
+
+/// Runs stages of locked block until it is paid, refunded, failed or a stage has to be resumed later
+async fn process(block: u64, mut stage: NotifyStage) -> Result<(), String> {
+    loop {
+        stage = match stage {
+            NotifyStage::received => verify(block).await?,
+            NotifyStage::verified => notify_token(block).await?,
+            NotifyStage::token_notified => pay(block).await?,
+            NotifyStage::paid => return Ok(()),
+            NotifyStage::refunded | NotifyStage::failed => {
+                let error = STATE.with(|s| s.borrow().block_notifications.get(&block).and_then(|x| x.error.clone()));
+                return Err(error.unwrap_or_default());
+            }
+        }
+    }
+}
+
+/// Reads block from ledger and checks it is a send to this canister
+async fn verify(block: u64) -> Result<NotifyStage, String> {
+    let transaction = ledger_client::get_send_transaction(block).await;
+
+    STATE.with(|s| s.borrow_mut().verify_transaction(block, transaction, id(), time()))
+}
+
+/// Sends notification to token canister, token errors are refunded
+async fn notify_token(block: u64) -> Result<NotifyStage, String> {
+    let (notification, token_canister) = STATE.with(|s| {
+        let state = s.borrow();
+        let notification = state.block_notifications.get(&block).cloned().ok_or_else(|| String::from("Block notification not found"))?;
+        let token_canister = state.token_canister.ok_or_else(|| String::from("Token canister not set!"))?;
+        Ok::<_, String>((notification, token_canister))
+    })?;
+
+    //From this point if there is an error in processing payment, payment should be returned to sender
+    //Sender is checked in verify, this covers blocks verified by versions without that check
+    if Some(account_of(notification.caller, notification.from_subaccount).to_hex()) != notification.from {
+        return refund(block, String::from("Invalid block! Caller does not match block sender")).await;
+    }
+
+    let result = notify_canister(token_canister, &notification).await;
+
+    match result {
+        Ok(res) => STATE.with(|s| {
+            let notification = s.borrow_mut().update_notification(block, time(), |x| {
+                x.stage = NotifyStage::token_notified;
+                x.response = Some(res);
+                x.error = None;
+            })?;
+            Ok(notification.stage)
+        }),
+        //Token canister was not reached or rejected the call without changes, notification can be sent again
+        Err(e) if e.id == ErrType::Call => {
+            STATE.with(|s| s.borrow_mut().update_notification(block, time(), |x| x.error = Some(e.text.clone())))?;
+            Err(e.text)
+        }
+        Err(e) if e.id == ErrType::Token => refund(block, e.text).await,
+        //Response could not be read, token canister could have processed the payment
+        Err(e) => {
+            STATE.with(|s| s.borrow_mut().update_notification(block, time(), |x| {
+                x.stage = NotifyStage::failed;
+                x.error = Some(e.text.clone());
+            }))?;
+            Err(e.text)
+        }
+    }
+}
+
+/// Stores fees, pays seller and returns surplus to buyer
+async fn pay(block: u64) -> Result<NotifyStage, String> {
+    let payouts = STATE.with(|s| s.borrow_mut().begin_payment(block, id(), time()))?;
+
+    for args in payouts {
+        let _ = send_icp(args).await;
+    }
+
+    Ok(NotifyStage::paid)
+}
+
+/// Refunds or pays out failed block, owner has to check the token canister first since a failed block could have been accepted there
+pub async fn resolve(block: u64, resolution: Resolution) -> Result<(), String> {
+    let payouts = STATE.with(|s| s.borrow_mut().begin_resolution(block, resolution, id(), time()))?;
+
+    for args in payouts {
+        let _ = send_icp(args).await;
+    }
+
+    Ok(())
+}
+
+/// Returns funds back to the sender, works only if funds are bigger than TX_FEE
+async fn refund(block: u64, reason: String) -> Result<NotifyStage, String> {
+    let args = STATE.with(|s| s.borrow_mut().begin_refund(block, reason.clone(), time()))?;
+
+    if let Some(args) = args {
+        let _ = send_icp(args).await;
+    }
+
+    Err(reason)
+}
+
+fn send_args(from_subaccount: Option<Subaccount>, to: String, to_account: Option<Account>, amount: u64, memo: u64) -> SendArgs {
+    SendArgs {
+        memo,
+        amount: ICPTs { e8s: amount },
+        fee: ICPTs {
+            e8s: common::TX_FEE,
+        },
+        from_subaccount,
+        to,
+        //Lets the ledger reject a retry of transfer that already went through
+        created_at_time: Some(TimeStamp { timestamp_nanos: time() }),
+        to_account,
+    }
+}
+
+/// Sends ICP, failed transfers go to retry queue
+async fn send_icp(args: SendArgs) -> Result<u64, String> {
+    let result = ledger_client::send(&args).await;
+
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        state.record_payment(args.clone(), result.clone());
+        if let Err(e) = &result {
+            state.enqueue_payout(args, e.clone(), time());
+        }
+    });
+
+    result
+}
+
+async fn notify_canister(
+    canister: Principal,
+    notification: &BlockNotification,
+) -> Result<TransactionResponse, AppErr> {
+    let transaction_notification_args = TransactionNotification {
+        from: notification.caller,
+        from_subaccount: notification.from_subaccount,
+        to: canister,
+        to_subaccount: notification.to_subaccount,
+        block_height: notification.block,
+        amount: ICPTs { e8s: notification.amount },
+        memo: notification.memo,
+    };
+
+    let res = {
+        let event_raw =
+            ic_cdk::export::candid::encode_args((transaction_notification_args.clone(),))
+                .unwrap();
+        //Notify token canister
+        let raw_res = call_raw(canister, "transaction_notification", event_raw.clone(), 0)
+            .await
+            .map_err(|(_, s)| AppErr {
+                id: ErrType::Call,
+                text: format!("Error while calling token canister, {}", s),
+            });
+
+        raw_res.and_then(|raw_res| {
+            Decode!(&raw_res, Result<TransactionResponse, String>).map_err(|_| AppErr {
+                id: ErrType::Decode,
+                text: String::from("Error while decoding response"),
+            })?.map_err(|s| AppErr {
+                id: ErrType::Token,
+                text: format!("Error from token canister, {}", s),
+            })
+        })
+    };
+
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        state.notification_offset += 1;
+        let index = state.notification_offset;
+        state.notifications.push(NotificationStatus {
+            index,
+            timestamp: time(),
+            args: transaction_notification_args,
+            result: Some(res.clone()),
+        });
+    });
+
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::subaccount::buyer_subaccount;
+
+    fn buyer() -> Principal {
+        Principal::from_text("tushn-jfas4-lrw4y-d3hun-lyc2x-hr2o2-2spfo-ak45s-jzksj-fzvln-yqe").unwrap()
+    }
+
+    fn seller() -> Principal {
+        Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap()
+    }
+
+    fn get_state() -> State {
+        State {
+            token_canister: Some(seller()),
+            owner: Some(Principal::management_canister()),
+            ..Default::default()
+        }
+    }
+
+    fn response(price: u64) -> TransactionResponse {
+        TransactionResponse {
+            block: 1,
+            creators_fee: 5000,
+            seller: seller(),
+            royalties: vec![],
+            price,
+            market_fee: 2500,
+        }
+    }
+
+    fn send_transaction(to: Option<Subaccount>, amount: u64) -> Result<(AccountIdentifier, AccountIdentifier, u64, u64), String> {
+        Ok((account_of(buyer(), None), account_of(id(), to), amount, 7))
+    }
+
+    #[test]
+    fn test_begin_notification() {
+        let mut state = get_state();
+
+        assert_eq!(state.begin_notification(buyer(), 10, None, 0), Ok(NotifyStage::received));
+        assert!(state.block_notifications[&10].in_flight);
+
+        //Block is locked while a call processes it, even for its caller
+        assert_eq!(state.begin_notification(buyer(), 10, None, 0), Err(String::from("Block is being processed")));
+
+        state.end_notification(10);
+        assert_eq!(state.begin_notification(seller(), 10, None, 0), Err(String::from("Block is processed for another caller")));
+        assert_eq!(state.begin_notification(buyer(), 10, None, 0), Ok(NotifyStage::received));
+
+        //Owner can resume block of another caller
+        state.end_notification(10);
+        assert_eq!(state.begin_notification(Principal::management_canister(), 10, None, 0), Ok(NotifyStage::received));
+
+        state.end_notification(10);
+        state.block_notifications.get_mut(&10).unwrap().stage = NotifyStage::paid;
+        assert_eq!(state.begin_notification(buyer(), 10, None, 0), Err(String::from("Block already processed")));
+    }
+
+    #[test]
+    fn test_stage_transitions() {
+        let mut state = get_state();
+        let _ = state.begin_notification(buyer(), 10, None, 0);
+
+        //Ledger errors keep block resumable
+        assert!(state.verify_transaction(10, Err(String::from("ledger busy")), id(), 0).is_err());
+        assert_eq!(state.block_notifications[&10].stage, NotifyStage::received);
+
+        let deposit = Some(buyer_subaccount(buyer()));
+        assert_eq!(state.verify_transaction(10, send_transaction(deposit, 100000000), id(), 0), Ok(NotifyStage::verified));
+        assert_eq!(state.block_notifications[&10].to_subaccount.map(|x| x.0), deposit.map(|x| x.0));
+        assert!(state.begin_payment(10, id(), 0).is_err());
+
+        state.block_notifications.get_mut(&10).unwrap().stage = NotifyStage::token_notified;
+        state.block_notifications.get_mut(&10).unwrap().response = Some(response(100000000));
+
+        //Payment accepted by token canister is never refunded
+        assert_eq!(state.begin_refund(10, String::from("late error"), 0).err(), Some(String::from("Block cannot be refunded in its stage")));
+        assert_eq!(state.block_notifications[&10].stage, NotifyStage::token_notified);
+
+        assert!(state.begin_payment(10, id(), 0).is_ok());
+        assert_eq!(state.block_notifications[&10].stage, NotifyStage::paid);
+        assert!(state.begin_payment(10, id(), 0).is_err());
+        assert!(state.begin_refund(10, String::from("late error"), 0).is_err());
+    }
+
+    #[test]
+    fn test_payout_amounts() {
+        let fee = common::TX_FEE;
+        //Every made transfer spends its amount and fee from the received payment
+        let spent = |x: &PayoutAmounts| [x.seller, x.refund, x.sweep].iter().filter(|x| **x > 0).map(|x| x + fee).sum::<u64>();
+
+        //Default account keeps exactly the fees
+        let amounts = PayoutAmounts::new(110000000, 100000000, 92500000, false);
+        assert_eq!(amounts, PayoutAmounts { seller: 92500000 - fee, refund: 10000000 - fee, sweep: 0 });
+        assert_eq!(110000000 - spent(&amounts), 7500000);
+
+        //Deposit subaccount is emptied, fees reach the default account in full
+        let amounts = PayoutAmounts::new(110000000, 100000000, 92500000, true);
+        assert_eq!(amounts, PayoutAmounts { seller: 92500000 - 2 * fee, refund: 10000000 - fee, sweep: 7500000 });
+        assert_eq!(spent(&amounts), 110000000);
+
+        //Surplus too small to refund is moved with the fees
+        let amounts = PayoutAmounts::new(100000000 + fee, 100000000, 92500000, true);
+        assert_eq!(amounts, PayoutAmounts { seller: 92500000 - 2 * fee, refund: 0, sweep: 7500000 + fee });
+        assert_eq!(spent(&amounts), 100000000 + fee);
+
+        //Primary sale without fees needs no move out of deposit
+        let amounts = PayoutAmounts::new(100000000, 100000000, 100000000, true);
+        assert_eq!(amounts, PayoutAmounts { seller: 100000000 - fee, refund: 0, sweep: 0 });
+        assert_eq!(spent(&amounts), 100000000);
+    }
+
+    #[test]
+    fn test_deposit_payment() {
+        let mut state = get_state();
+        let deposit = Some(buyer_subaccount(buyer()));
+        let _ = state.begin_notification(buyer(), 10, None, 0);
+        let _ = state.verify_transaction(10, send_transaction(deposit, 110000000), id(), 0);
+        state.block_notifications.get_mut(&10).unwrap().stage = NotifyStage::token_notified;
+        state.block_notifications.get_mut(&10).unwrap().response = Some(response(100000000));
+
+        let payouts = state.begin_payment(10, id(), 0).unwrap();
+        assert_eq!(payouts.len(), 3);
+        assert!(payouts.iter().all(|x| x.from_subaccount.map(|x| x.0) == deposit.map(|x| x.0)));
+        assert_eq!(payouts.iter().map(|x| x.amount.e8s + x.fee.e8s).sum::<u64>(), 110000000);
+        assert_eq!(payouts[2].to, common::account_id(id(), None));
+        assert_eq!(payouts[2].amount.e8s, state.waiting_market_fee + state.waiting_creator_fee);
+    }
+
+    #[test]
+    fn test_wrong_claim_dropped() {
+        let mut state = get_state();
+
+        //Block sent by buyer claimed by another caller
+        let _ = state.begin_notification(seller(), 10, None, 0);
+        assert_eq!(state.verify_transaction(10, send_transaction(None, 100000000), id(), 0), Err(String::from("Invalid block! Caller does not match block sender")));
+        assert!(!state.block_notifications.contains_key(&10));
+        assert_eq!(state.is_processed(10), Ok(()));
+
+        //Block sent to another account
+        let _ = state.begin_notification(buyer(), 10, None, 0);
+        let to = send_transaction(None, 100000000).map(|(from, _, amount, memo)| (from, account_of(seller(), None), amount, memo));
+        assert!(state.verify_transaction(10, to, id(), 0).is_err());
+        assert!(!state.block_notifications.contains_key(&10));
+
+        //Buyer can claim the block afterwards
+        assert_eq!(state.begin_notification(buyer(), 10, None, 0), Ok(NotifyStage::received));
+        assert_eq!(state.verify_transaction(10, send_transaction(None, 100000000), id(), 0), Ok(NotifyStage::verified));
+    }
+
+    #[test]
+    fn test_refund() {
+        let mut state = get_state();
+        let _ = state.begin_notification(buyer(), 10, None, 0);
+        let _ = state.verify_transaction(10, send_transaction(None, 100000000), id(), 0);
+
+        let args = state.begin_refund(10, String::from("Token is not listed"), 0).unwrap().unwrap();
+        assert_eq!(args.amount.e8s, 100000000 - common::TX_FEE);
+        assert_eq!(args.to, account_of(buyer(), None).to_hex());
+        assert_eq!(state.block_notifications[&10].stage, NotifyStage::refunded);
+
+        //Refunded block cannot be refunded again
+        assert!(state.begin_refund(10, String::from("Token is not listed"), 0).is_err());
+    }
+
+    #[test]
+    fn test_resolution() {
+        let mut state = get_state();
+        for block in [10, 11] {
+            let _ = state.begin_notification(buyer(), block, None, 0);
+            let _ = state.verify_transaction(block, send_transaction(None, 100000000), id(), 0);
+            state.end_notification(block);
+        }
+        assert_eq!(state.begin_resolution(10, Resolution::refund, id(), 0).err(), Some(String::from("Only failed blocks can be resolved")));
+
+        //Token canister reported price above paid amount
+        state.block_notifications.get_mut(&10).unwrap().stage = NotifyStage::token_notified;
+        state.block_notifications.get_mut(&10).unwrap().response = Some(response(200000000));
+        assert!(state.begin_payment(10, id(), 0).is_err());
+        assert_eq!(state.block_notifications[&10].stage, NotifyStage::failed);
+
+        //Stored response fails again, owner can pay with corrected one
+        assert!(state.begin_resolution(10, Resolution::pay(None), id(), 0).is_err());
+        assert_eq!(state.block_notifications[&10].stage, NotifyStage::failed);
+        let payouts = state.begin_resolution(10, Resolution::pay(Some(response(100000000))), id(), 0).unwrap();
+        assert_eq!(payouts.len(), 1);
+        assert_eq!(payouts[0].amount.e8s, 92500000 - common::TX_FEE);
+        assert_eq!(state.block_notifications[&10].stage, NotifyStage::paid);
+
+        //Block with unreadable token response has none stored, owner can refund it
+        state.block_notifications.get_mut(&11).unwrap().stage = NotifyStage::failed;
+        assert_eq!(state.begin_resolution(11, Resolution::pay(None), id(), 0).err(), Some(String::from("Token canister response not stored")));
+        let payouts = state.begin_resolution(11, Resolution::refund, id(), 0).unwrap();
+        assert_eq!(payouts[0].amount.e8s, 100000000 - common::TX_FEE);
+        assert_eq!(payouts[0].to, account_of(buyer(), None).to_hex());
+        assert_eq!(state.block_notifications[&11].stage, NotifyStage::refunded);
+        assert!(state.begin_resolution(11, Resolution::refund, id(), 0).is_err());
+    }
+}
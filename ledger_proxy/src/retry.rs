@@ -0,0 +1,147 @@
+#[cfg(test)]
+use crate::testing::time;
+
+#[cfg(not(test))]
+use ic_cdk::api::time;
+use ic_cdk::export::candid::{CandidType, Deserialize};
+use serde::Serialize;
+
+use common::{SendArgs, TimeStamp};
+
+use crate::ledger_client::{self, LedgerApi};
+use crate::state::{State, STATE};
+
+/// First retry delay in nanoseconds, doubled after every attempt
+pub const RETRY_BASE_DELAY: u64 = 60 * 1_000_000_000;
+pub const RETRY_MAX_DELAY: u64 = 60 * 60 * 1_000_000_000;
+/// ICP ledger deduplicates transfers for 24 hours, retries stop a bit earlier so a lost reply cannot pay twice
+pub const DEDUP_WINDOW: u64 = 23 * 60 * 60 * 1_000_000_000;
+
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize, Serialize)]
+#[allow(non_camel_case_types)]
+pub enum PayoutStatus {
+    /// Retried automatically at next_attempt
+    pending,
+    /// Dedup window passed or protobuf retry failed, needs reissue after checking it was not paid, or resolve
+    failed,
+}
+
+#[derive(Clone, CandidType, Deserialize, Serialize)]
+pub struct PendingPayout {
+    pub id: u64,
+    /// Sent unchanged on every retry, created_at_time lets the ledger reject duplicates
+    pub args: SendArgs,
+    pub attempts: u32,
+    pub next_attempt: u64,
+    pub last_error: String,
+    pub status: PayoutStatus,
+}
+
+impl State {
+    /// Adds failed transfer to retry queue
+    pub fn enqueue_payout(&mut self, args: SendArgs, error: String, now: u64) {
+        self.payout_offset += 1;
+        self.pending_payouts.push(PendingPayout {
+            id: self.payout_offset,
+            args,
+            attempts: 1,
+            next_attempt: now + RETRY_BASE_DELAY,
+            last_error: error,
+            status: PayoutStatus::pending,
+        });
+    }
+
+    /// Returns ids of payouts due for retry, moves payouts past dedup window to failed
+    pub fn due_payouts(&mut self, now: u64) -> Vec<u64> {
+        let mut due = Vec::new();
+
+        for payout in self.pending_payouts.iter_mut().filter(|x| x.status == PayoutStatus::pending) {
+            let created_at = payout.args.created_at_time.as_ref().map_or(0, |x| x.timestamp_nanos);
+            if now >= created_at + DEDUP_WINDOW {
+                payout.status = PayoutStatus::failed;
+            } else if now >= payout.next_attempt {
+                //Keeps next heartbeat from picking payout while this attempt waits for the ledger
+                payout.next_attempt = now + RETRY_MAX_DELAY;
+                due.push(payout.id);
+            }
+        }
+
+        due
+    }
+
+    /// Returns args of pending payout to send, payout past dedup window is moved to failed instead
+    pub fn begin_retry(&mut self, id: u64, now: u64) -> Result<SendArgs, String> {
+        let payout = self.pending_payouts.iter_mut().find(|x| x.id == id).ok_or_else(|| String::from("Payout not found"))?;
+
+        let created_at = payout.args.created_at_time.as_ref().map_or(0, |x| x.timestamp_nanos);
+        if now >= created_at + DEDUP_WINDOW { payout.status = PayoutStatus::failed; }
+
+        //Ledger would not recognize a copy of failed payout, sending it again could pay twice
+        if payout.status == PayoutStatus::failed {
+            return Err(String::from("Payout is outside of ledger dedup window, check it was not paid and reissue it"));
+        }
+
+        Ok(payout.args.clone())
+    }
+
+    /// Stores result of retry, successful payouts leave the queue. Candid and ICRC-1 ledger APIs report duplicates of paid transfers as success,
+    /// send_dfx rejects them like any other error, so on the protobuf API a failed retry moves payout to failed for the owner to check
+    pub fn payout_attempted(&mut self, id: u64, result: &Result<u64, String>, now: u64) {
+        let position = match self.pending_payouts.iter().position(|x| x.id == id) { Some(x) => x, None => return };
+        let reports_duplicates = self.ledger_api != LedgerApi::protobuf;
+
+        match result {
+            Ok(_) => { self.pending_payouts.remove(position); }
+            Err(e) => {
+                let payout = &mut self.pending_payouts[position];
+                payout.attempts += 1;
+                payout.last_error = e.clone();
+                if !reports_duplicates { payout.status = PayoutStatus::failed; }
+                let delay = RETRY_BASE_DELAY.saturating_mul(1 << payout.attempts.min(16)).min(RETRY_MAX_DELAY);
+                payout.next_attempt = now + delay;
+            }
+        }
+    }
+
+    /// Gives failed payout new created_at_time and dedup window, owner confirms the payout is not in the ledger by calling it
+    pub fn reissue_payout(&mut self, id: u64, now: u64) -> Result<PendingPayout, String> {
+        let payout = self.pending_payouts.iter_mut().find(|x| x.id == id).ok_or_else(|| String::from("Payout not found"))?;
+        if payout.status != PayoutStatus::failed { return Err(String::from("Only failed payouts can be reissued")); }
+
+        payout.args.created_at_time = Some(TimeStamp { timestamp_nanos: now });
+        payout.status = PayoutStatus::pending;
+        payout.next_attempt = now;
+
+        Ok(payout.clone())
+    }
+
+    /// Removes payout from queue without sending it, used when it was settled by other means
+    pub fn resolve_payout(&mut self, id: u64) -> Result<PendingPayout, String> {
+        let position = self.pending_payouts.iter().position(|x| x.id == id).ok_or_else(|| String::from("Payout not found"))?;
+        Ok(self.pending_payouts.remove(position))
+    }
+}
+
+/// Sends queued payout again, unchanged args let the ledger reject it if the previous attempt went through
+pub async fn retry_payout(id: u64) -> Result<u64, String> {
+    let args = STATE.with(|s| s.borrow_mut().begin_retry(id, time()))?;
+
+    let result = ledger_client::send(&args).await;
+
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        state.record_payment(args, result.clone());
+        state.payout_attempted(id, &result, time());
+    });
+
+    result
+}
+
+/// Retries all payouts that are due, called from heartbeat
+pub async fn retry_due_payouts() {
+    let due = STATE.with(|s| s.borrow_mut().due_payouts(time()));
+
+    for id in due {
+        let _ = retry_payout(id).await;
+    }
+}
@@ -1,16 +1,20 @@
 use crate::int_set::IntSet;
 use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
 use std::rc::Rc;
 
-use ic_cdk::api::call::call_raw;
+#[cfg(test)]
+use crate::testing::time;
+
+#[cfg(not(test))]
 use ic_cdk::api::time;
-use ic_cdk::export::candid::{CandidType, Decode, Deserialize, Principal};
-use ic_cdk::{caller, id};
+use ic_cdk::export::candid::{CandidType, Deserialize, Principal};
 
-use prost::Message;
+use common::{SendArgs, TransactionNotification, TransactionResponse};
 
-use common::account_identifier::AccountIdentifier;
-use common::{call_send_dfx, ICPTs, SendArgs, TransactionNotification, TransactionResponse};
+use crate::retry::PendingPayout;
+use crate::notify::BlockNotification;
+use crate::ledger_client::LedgerApi;
 
 thread_local! {
     pub static STATE: Rc<RefCell<State>> = Rc::new(RefCell::new(State::default()));
@@ -51,8 +55,8 @@ pub enum ErrType {
 
 #[derive(Clone, CandidType, Deserialize, Serialize)]
 pub struct AppErr {
-    id: ErrType,
-    text: String,
+    pub id: ErrType,
+    pub text: String,
 }
 
 use serde::Serialize;
@@ -60,14 +64,31 @@ use serde::Serialize;
 #[derive(Clone, Deserialize, Serialize)]
 pub struct State {
     pub ledger_canister: Option<Principal>,
+    ///Interface used to read blocks and send ICP, protobuf for proxies installed before it was configurable
+    #[serde(default)]
+    pub ledger_api: LedgerApi,
     pub token_canister: Option<Principal>,
 
     pub owner: Option<Principal>,
+    /// Proposed owner and proposal expiry, ownership moves when proposed owner accepts
+    #[serde(default)]
+    pub pending_owner: Option<(Principal, u64)>,
     pub blocks: Vec<SendArgs>,
     pub blocks_processed: IntSet,
 
-    pub market_fee: u64,
     pub market_address: Option<Principal>,
+    ///Receives creators fee that is not split between royalty recipients
+    #[serde(default)]
+    pub creator_address: Option<Principal>,
+
+    ///Minimum time between disbursements started by heartbeat in nanoseconds, 0 disables them
+    #[serde(default)]
+    pub disburse_interval: u64,
+    #[serde(default)]
+    pub last_disbursement: u64,
+    ///Set while disbursement waits for ledger calls
+    #[serde(skip)]
+    pub disbursing: bool,
 
     pub total_market_fee: u64,
     pub total_creator_fee: u64,
@@ -75,46 +96,89 @@ pub struct State {
     pub waiting_market_fee: u64,
     pub waiting_creator_fee: u64,
 
+    ///Part of waiting_creator_fee owed to each royalty recipient
+    #[serde(default)]
+    pub creator_balances: HashMap<Principal, u64>,
+
     ///Stores all ICP transfers with results (success or error)
     pub payment_offset: u64,
     pub payments: Vec<TransferStatus>,
 
+    ///Failed seller payouts and refunds waiting for retry
+    #[serde(default)]
+    pub payout_offset: u64,
+    #[serde(default)]
+    pub pending_payouts: Vec<PendingPayout>,
+
     pub notification_offset: u64,
     pub notifications: Vec<NotificationStatus>,
+
+    ///Progress of every notified block, unfinished entries are resumed by calling notify again
+    #[serde(default)]
+    pub block_notifications: BTreeMap<u64, BlockNotification>,
 }
 
 impl Default for State {
     fn default() -> Self {
         Self {
             ledger_canister: Some(Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap()),
+            ledger_api: LedgerApi::default(),
             token_canister: None,
 
             owner: None,
+            pending_owner: None,
             blocks: Vec::default(),
             blocks_processed: IntSet::default(),
             // blocks_processed: IntMap::new(),
 
-            market_fee: 2500,
             market_address: None,
+            creator_address: None,
+
+            disburse_interval: 0,
+            last_disbursement: 0,
+            disbursing: false,
 
             total_market_fee: 0,
             total_creator_fee: 0,
 
             waiting_market_fee: 0,
             waiting_creator_fee: 0,
+            creator_balances: HashMap::default(),
 
             payment_offset: 0,
             payments: Vec::default(),
 
+            payout_offset: 0,
+            pending_payouts: Vec::default(),
+
             notification_offset: 0,
             notifications: Vec::default(),
+
+            block_notifications: BTreeMap::default(),
         }
     }
 }
 
-use crate::ledger;
+/// How long proposed owner has to accept ownership, 7 days in nanoseconds
+pub const OWNERSHIP_PROPOSAL_WINDOW: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
 
 impl State {
+    ///Proposes new owner, replaces previous proposal
+    pub fn propose_owner(&mut self, owner: Principal, now: u64) {
+        self.pending_owner = Some((owner, now + OWNERSHIP_PROPOSAL_WINDOW));
+    }
+
+    ///Moves ownership to proposed owner calling before proposal expires
+    pub fn accept_ownership(&mut self, caller: Principal, now: u64) -> Result<(), String> {
+        let (owner, expires_at) = self.pending_owner.ok_or_else(|| String::from("No pending ownership proposal"))?;
+        if owner != caller { return Err(String::from("The caller is not the proposed owner")); }
+        if expires_at <= now { return Err(String::from("Ownership proposal expired")); }
+
+        self.owner = Some(owner);
+        self.pending_owner = None;
+        Ok(())
+    }
+
     ///Checks if block was already processed, returns Ok if block is not processed, err if processed
     pub fn is_processed(&self, block: u64) -> Result<(), String> {
         let block = self.blocks_processed
@@ -130,75 +194,17 @@ impl State {
         }
     }
 
-    ///Downloads block from ledger and send notification to token canister
-    pub async fn notify(&mut self, block: u64) -> Result<(), String> {
-        let caller = caller();
-        let id = id();
-
-        //Preconditions block must not be processed, token_canister must be set
-        self.is_processed(block)?;
-        let token_canister = self
-            .token_canister
-            .ok_or_else(|| String::from("Token canister not set!"))?;
-        //Add block to processed list
-        self.blocks_processed.insert(block, ());
-
-        //Get send transaction data from ICP ledger archive
-        let (_from, _to, amount, memo) = self.get_send_transaction(block).await?;
-
-
-        //Verify that transaction is for our canister, otherwise throw error
-        let caller_account_id = AccountIdentifier::new(caller.clone(), None);
-        let canister_account_id = AccountIdentifier::new(id.clone(), None);
-
-        //Check if receiving canister is current one
-        if canister_account_id != _to {
-            return Err(String::from(
-                "Invalid block! Canister does not match block recipient",
-            ));
+    ///Adds fees of token canister response to waiting balances, returns amount owed to seller
+    pub fn apply_fees(&mut self, paid: u64, resp: &TransactionResponse) -> Result<u64, String> {
+        //Fees and seller share are calculated from the listing price, not from the paid amount
+        if resp.price > paid {
+            return Err(String::from("Token canister reported price above paid amount"));
         }
+        let amount = resp.price;
 
-        //From this point if there is an error in processing payment, payment should be returned to sender
-        let result = {
-            if caller_account_id != _from {
-                Err(AppErr {
-                    id: ErrType::Resp,
-                    text: String::from("Invalid block! Caller does not match block sender"),
-                })
-            } else {
-                //Send notification to token canister
-                self.notify_canister(caller.clone(), token_canister, block, amount, memo)
-                    .await
-            }
-        };
-
-        match result {
-            Ok(res) => {
-                //Process response from the token canister, sends ICP to seller
-                self.process_response(amount, &res).await?;
-
-                Ok(())
-            }
-            Err(s) => {
-                if s.id != ErrType::Call {
-                    self.refund(_from.to_hex(), amount, memo).await;
-                }
-
-                Err(s.text)
-            }
-        }
-
-        // return result;
-    }
-
-    ///Processes response from token canister, if it is success send ICP to seller and store fees for disbursment
-    async fn process_response(
-        &mut self,
-        amount: u64,
-        resp: &TransactionResponse,
-    ) -> Result<(), String> {
-        let market_fee = self.market_fee * amount / 100000;
-        let creators_fee = resp.creators_fee * amount / 100000;
+        //Market fee is configured in the token canister, fees are calculated in u128 so large prices do not overflow
+        let market_fee = (resp.market_fee as u128 * amount as u128 / 100000) as u64;
+        let creators_fee = (resp.creators_fee as u128 * amount as u128 / 100000) as u64;
 
         let seller = amount - market_fee - creators_fee;
 
@@ -208,197 +214,124 @@ impl State {
         self.waiting_creator_fee += creators_fee;
         self.total_creator_fee += creators_fee;
 
-        //Send remaining icp to seller
-        let _block = self
-            .send_icp(common::account_id(resp.seller, None), seller, 0)
-            .await?;
-        Ok(())
-    }
-
-    /// Returns funds back to the sender, works only if funds are bigger than TX_FEE
-    async fn refund(&mut self, to_account: String, amount: u64, memo: u64) {
-        if amount > common::TX_FEE {
-            let _block = self
-                .send_icp(to_account, amount - common::TX_FEE, memo)
-                .await;
+        //Split creators fee between royalty recipients, fee not covered by shares stays unallocated
+        for share in resp.royalties.iter() {
+            let fee = (share.fee as u128 * amount as u128 / 100000) as u64;
+            *self.creator_balances.entry(share.account).or_default() += fee;
         }
+
+        Ok(seller)
     }
 
-    fn get_ledger_canister(&self) -> Result<Principal, String> {
+    pub fn get_ledger_canister(&self) -> Result<Principal, String> {
         self.ledger_canister
             .ok_or_else(|| String::from("Ledger canister not set"))
     }
 
-    /// Send ICP to target principal, subaccount
-    async fn send_icp(
-        &mut self,
-        to_account: String,
-        amount: u64,
-        memo: u64,
-    ) -> Result<u64, String> {
-        let ledger_canister = self.get_ledger_canister()?;
-
-        // let to_account = common::account_id(to, None).clone();
-        let args = SendArgs {
-            memo: memo,
-            amount: ICPTs { e8s: amount },
-            fee: ICPTs {
-                e8s: common::TX_FEE,
-            },
-            from_subaccount: None,
-            to: to_account,
-            created_at_time: None,
-        };
-
+    ///Stores ICP transfer with its result in payments
+    pub fn record_payment(&mut self, args: SendArgs, result: Result<u64, String>) -> TransferStatus {
         self.payment_offset += 1;
-        let mut payment = TransferStatus {
+        let payment = TransferStatus {
             index: self.payment_offset,
-            args: args.clone(),
+            args: args,
             timestamp: time(),
-            result: None,
+            result: Some(result),
         };
 
-        let result = call_send_dfx(ledger_canister, &args).await;
+        self.payments.push(payment.clone());
+
+        payment
+    }
+}
 
-        payment.result = Some(result.clone());
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::{ICPTs, TimeStamp};
 
-        self.payments.push(payment);
+    #[test]
+    fn test_is_processed() {
+        let mut state = State::default();
 
-        return result;
+        let processed = state.is_processed(1234);
+        
+        assert_eq!(processed, Ok(()));
     }
 
-    async fn notify_canister(
-        &mut self,
-        caller: Principal,
-        canister: Principal,
-        block: u64,
-        amount: u64,
-        memo: u64,
-    ) -> Result<TransactionResponse, AppErr> {
-        let transaction_notification_args = TransactionNotification {
-            from: caller,
+    #[test]
+    fn test_payout_retry_backoff() {
+        let mut state = State { ledger_api: LedgerApi::candid, ..Default::default() };
+        let args = SendArgs {
+            memo: 0,
+            amount: ICPTs { e8s: 100000000 },
+            fee: ICPTs { e8s: common::TX_FEE },
             from_subaccount: None,
-            to: canister,
-            to_subaccount: None,
-            block_height: block,
-            amount: ICPTs { e8s: amount },
-            memo: memo,
-        };
-
-        let res = {
-            let event_raw =
-                ic_cdk::export::candid::encode_args((transaction_notification_args.clone(),))
-                    .unwrap();
-            //Notify token canister
-            let raw_res = call_raw(canister, "transaction_notification", event_raw.clone(), 0)
-                .await
-                .map_err(|(_, s)| AppErr {
-                    id: ErrType::Call,
-                    text: format!("Error while calling token canister, {}", s),
-                })?;
-
-            let res = Decode!(&raw_res, Result<TransactionResponse, String>).map_err(|_| AppErr {
-                id: ErrType::Decode,
-                text: format!("Error while decoding response"),
-            })?.map_err(|s| AppErr {
-                id: ErrType::Token,
-                text: format!("Error from token canister, {}", s),
-            });
-
-            res
+            to: String::default(),
+            created_at_time: Some(TimeStamp { timestamp_nanos: 0 }),
+            to_account: None,
         };
-        self.notification_offset += 1;
-        self.notifications.push(NotificationStatus {
-            index: self.notification_offset,
-            timestamp: time(),
-            args: transaction_notification_args,
-            result: Some(res.clone()),
-        });
-
-        let res = res?;
 
-        Ok(res)
+        state.enqueue_payout(args.clone(), String::from("ledger busy"), 0);
+        state.enqueue_payout(args, String::from("ledger busy"), 0);
+        assert_eq!(state.due_payouts(0), Vec::<u64>::new());
+        assert_eq!(state.due_payouts(crate::retry::RETRY_BASE_DELAY), vec![1, 2]);
+
+        state.payout_attempted(1, &Err(String::from("ledger busy")), crate::retry::RETRY_BASE_DELAY);
+        state.payout_attempted(2, &Ok(5), crate::retry::RETRY_BASE_DELAY);
+        assert_eq!(state.pending_payouts.len(), 1);
+        assert_eq!(state.pending_payouts[0].next_attempt, crate::retry::RETRY_BASE_DELAY * 5);
+
+        assert_eq!(state.due_payouts(crate::retry::DEDUP_WINDOW), Vec::<u64>::new());
+        assert_eq!(state.pending_payouts[0].status, crate::retry::PayoutStatus::failed);
+
+        //Failed payout is not sent again until owner reissues it with new created_at_time
+        assert!(state.begin_retry(1, crate::retry::DEDUP_WINDOW).is_err());
+        assert!(state.reissue_payout(1, crate::retry::DEDUP_WINDOW).is_ok());
+        assert!(state.reissue_payout(1, crate::retry::DEDUP_WINDOW).is_err());
+        let args = state.begin_retry(1, crate::retry::DEDUP_WINDOW).unwrap();
+        assert_eq!(args.created_at_time.map(|x| x.timestamp_nanos), Some(crate::retry::DEDUP_WINDOW));
+
+        //send_dfx does not report duplicates, failed retry waits for the owner
+        state.ledger_api = LedgerApi::protobuf;
+        state.payout_attempted(1, &Err(String::from("ledger busy")), crate::retry::DEDUP_WINDOW);
+        assert_eq!(state.pending_payouts[0].status, crate::retry::PayoutStatus::failed);
+        assert!(state.begin_retry(1, crate::retry::DEDUP_WINDOW).is_err());
     }
 
-    ///Downloads block from ledger and returns parsed information, returns only send transactions
-    async fn get_send_transaction(
-        &self,
-        block_height: u64,
-    ) -> Result<(AccountIdentifier, AccountIdentifier, u64, u64), String> {
-        //Get block from ledger
-        let raw_block: ledger::EncodedBlock = self.get_block(block_height).await?;
-        let block_data = ledger::Block::decode(&raw_block.block[..])
-            .map_err(|x| format!("Could not decode block, {}", x))?;
-        let transaction = block_data
-            .transaction
-            .ok_or_else(|| String::from("Transaction is None"))?;
-
-        let (_from, _to, amount) = match transaction
-            .transfer
-            .ok_or_else(|| String::from("Transaction transfer is none"))?
-        {
-            ledger::transaction::Transfer::Send(item) => (
-                AccountIdentifier::from_slice(&item.from.unwrap().hash[..]).unwrap(),
-                AccountIdentifier::from_slice(&item.to.unwrap().hash[..]).unwrap(),
-                item.amount
-                    .ok_or_else(|| String::from("Amount is empty!"))?
-                    .e8s,
-            ),
-            ledger::transaction::Transfer::Burn(_) => {
-                return Err(String::from(
-                    "Notification failed transfer must be of type send, found burn",
-                ))
-            }
-            ledger::transaction::Transfer::Mint(_) => {
-                return Err(String::from(
-                    "Notification failed transfer must be of type send, found mint",
-                ))
-            }
+
+    #[test]
+    fn test_apply_fees() {
+        let mut state = State::default();
+        let mut resp = TransactionResponse {
+            block: 1,
+            creators_fee: 5000,
+            seller: Principal::anonymous(),
+            royalties: vec![],
+            price: u64::MAX / 2,
+            market_fee: 2500,
         };
 
-        Ok((
-            _from,
-            _to,
-            amount,
-            transaction.memo.map_or_else(|| 0, |s| s.memo),
-        ))
-    }
+        //Large prices do not overflow the fee calculation
+        let seller = state.apply_fees(u64::MAX / 2, &resp);
+        assert_eq!(state.waiting_market_fee, (u64::MAX / 2) / 40);
+        assert_eq!(state.waiting_creator_fee, (u64::MAX / 2) / 20);
+        assert_eq!(seller, Ok(u64::MAX / 2 - state.waiting_market_fee - state.waiting_creator_fee));
 
-    async fn get_block(&self, block_height: u64) -> Result<ledger::EncodedBlock, String> {
-        //ICP Ledger canister
-        let canister = self.get_ledger_canister()?;
-
-        let mut req = ledger::BlockRequest::default();
-        req.block_height = block_height;
-        let mut buf = Vec::<u8>::new();
-        buf.reserve(req.encoded_len());
-        req.encode(&mut buf).unwrap();
-
-        let res = call_raw(canister, "block_pb", buf, 0)
-            .await
-            .map_err(|(_, text)| text)?;
-
-        let resp = ledger::BlockResponse::decode(&res[..])
-            .map_err(|x| format!("Prost decoding error {}", x))?;
-        match resp.block_content.unwrap() {
-            ledger::block_response::BlockContent::Block(raw_block) => Ok(raw_block),
-            ledger::block_response::BlockContent::CanisterId(_prin) => {
-                Err(String::from("Block is already archived!"))
-            }
-        }
+        resp.price = 100000000;
+        assert!(state.apply_fees(resp.price - 1, &resp).is_err());
     }
-}
-
-#[cfg(test)]
-mod test {
-    use super::*;
 
     #[test]
-    fn test_is_processed() {
+    fn test_ownership_handover() {
         let mut state = State::default();
+        let owner = Principal::from_text("tushn-jfas4-lrw4y-d3hun-lyc2x-hr2o2-2spfo-ak45s-jzksj-fzvln-yqe").unwrap();
 
-        let processed = state.is_processed(1234);
-        
-        assert_eq!(processed, Ok(()));
+        assert_eq!(state.accept_ownership(owner, 0), Err(String::from("No pending ownership proposal")));
+
+        state.propose_owner(owner, 0);
+        assert_eq!(state.accept_ownership(Principal::anonymous(), 0), Err(String::from("The caller is not the proposed owner")));
+        assert_eq!(state.accept_ownership(owner, OWNERSHIP_PROPOSAL_WINDOW), Err(String::from("Ownership proposal expired")));
+        assert_eq!(state.accept_ownership(owner, 1), Ok(()));
+        assert_eq!(state.owner, Some(owner));
     }
 }
\ No newline at end of file
@@ -0,0 +1,37 @@
+use ic_cdk::export::candid::Principal;
+use sha2::{Digest, Sha256};
+
+use common::account_identifier::{self, AccountIdentifier};
+use common::Subaccount;
+
+/// Domain separator of per-token deposit subaccounts
+static TOKEN_DEPOSIT_DOMAIN: &[u8] = b"\x0Dtoken-deposit";
+
+/// Deposit subaccount of buyer, principal bytes prefixed with their length
+pub fn buyer_subaccount(buyer: Principal) -> Subaccount {
+    Subaccount(account_identifier::Subaccount::from(&buyer).0)
+}
+
+/// Deposit subaccount of listed token, payment memo holds the token id in the low 32 bits
+pub fn token_subaccount(token_id: u32) -> Subaccount {
+    let mut hash = Sha256::new();
+    hash.update(TOKEN_DEPOSIT_DOMAIN);
+    hash.update(token_id.to_be_bytes());
+
+    Subaccount(hash.finalize().into())
+}
+
+/// Account of principal in given subaccount
+pub fn account_of(owner: Principal, subaccount: Option<Subaccount>) -> AccountIdentifier {
+    AccountIdentifier::new(owner, subaccount.map(|x| account_identifier::Subaccount(x.0)))
+}
+
+/// Finds subaccount of canister that received payment, None is the default account
+pub fn deposit_subaccount(canister: Principal, to: AccountIdentifier, buyer: Principal, memo: u64) -> Result<Option<Subaccount>, String> {
+    let candidates = [None, Some(buyer_subaccount(buyer)), Some(token_subaccount(memo as u32))];
+
+    candidates.iter()
+        .find(|x| account_of(canister, **x) == to)
+        .copied()
+        .ok_or_else(|| String::from("Invalid block! Canister does not match block recipient"))
+}
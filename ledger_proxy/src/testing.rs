@@ -0,0 +1,66 @@
+use serde_bytes::ByteBuf;
+use common::rc_bytes::RcBytes;
+use crate::storage::Asset;
+use std::cell::RefCell;
+
+use ic_cdk::api::stable::StableMemoryError;
+use ic_cdk::export::candid::Principal;
+
+pub fn trap(data: &str) {
+    panic!("{}",data);
+}
+
+pub fn get_asset() -> Asset {
+    Asset {
+        name: "example asset".to_string(),
+        content_type: "image/jpg".to_string(),
+        data: RcBytes::from(ByteBuf::from(vec![243; 1024*1024])),
+    }
+}
+
+thread_local! {
+    static STORAGE: RefCell<Vec<u8>> = RefCell::new(vec![]);
+}
+
+pub fn time() -> u64 {
+    0
+}
+
+/// Return the page count, not the total bytes in storage.
+/// This is how ic_cdk works
+pub fn stable_size() -> u32 {
+    STORAGE.with(|s| s.borrow().len()) as u32 >> 16
+}
+
+pub fn stable_read(offset: u32, buf: &mut [u8]) {
+    STORAGE.with(|storage| {
+        let offset = offset as usize;
+        buf.copy_from_slice(&storage.borrow()[offset..offset + buf.len()]);
+    });
+}
+
+pub fn stable_write(offset: u32, buf: &[u8]) {
+    STORAGE.with(|storage| {
+        let offset = offset as usize;
+        storage.borrow_mut()[offset..offset + buf.len()].copy_from_slice(buf);
+    });
+}
+
+pub fn stable_grow(new_pages: u32) -> Result<u32, StableMemoryError> {
+    STORAGE.with(|storage| {
+        let additional_len = (new_pages << 16) as usize;
+        let len = storage.borrow().len();
+        match len + additional_len >= u32::MAX as usize {
+            false => {
+                let previous_size = storage.borrow().len() >> 16;
+                storage.borrow_mut().append(&mut vec![0u8; additional_len]);
+                Ok(previous_size as u32)
+            }
+            true => Err(StableMemoryError()),
+        }
+    })
+}
+
+pub fn id() -> Principal {
+    Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap()
+}
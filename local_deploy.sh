@@ -26,5 +26,6 @@ STOREID="principal \"$STOREID\""
 eval dfx canister --no-wallet call token set_storage_canister "'($STOREID)'"
 eval dfx canister --no-wallet call storage setTokenCanisterId "'($TOKENID)'"
 eval dfx canister --no-wallet call token add_genesis_record
+eval dfx canister --no-wallet call token set_paused "'(false)'"
 
-eval dfx canister --no-wallet call token set_owner "'(principal \"k3r3y-gsxlr-4jp3j-vvyk3-jnux2-7da37-muovr-7xphw-2v2wd-2hvms-sqe\")'"
\ No newline at end of file
+eval dfx canister --no-wallet call token propose_owner "'(principal \"k3r3y-gsxlr-4jp3j-vvyk3-jnux2-7da37-muovr-7xphw-2v2wd-2hvms-sqe\")'"
\ No newline at end of file
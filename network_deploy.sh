@@ -17,8 +17,8 @@ echo "Installation complete"
 eval dfx canister --network ic call token add_genesis_record
 
 if ! [ -z ${OWNER+x} ]; then
-    echo "Setting canister owner"
-    eval dfx canister --network ic call token set_owner "'(principal $OWNER)'"
+    echo "Proposing canister owner, $OWNER has to call accept_ownership within 7 days"
+    eval dfx canister --network ic call token propose_owner "'(principal $OWNER)'"
 fi
 
-echo "Preparation complete"
+echo "Preparation complete, canister is paused until set_paused (false) is called"
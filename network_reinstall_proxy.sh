@@ -10,7 +10,14 @@ dfx canister --network ic create ledger_proxy
 
 dfx build --network ic ledger_proxy
 
-eval dfx canister --network ic install ledger_proxy -m reinstall
+#Owner of the proxy is PROXY_OWNER or the deploying identity when it is not set
+if [ -z ${PROXY_OWNER+x} ]; then
+    PROXY_ARG="(null)"
+else
+    PROXY_ARG="(opt principal $PROXY_OWNER)"
+fi
+
+eval dfx canister --network ic install ledger_proxy --argument="'$PROXY_ARG'" -m reinstall
 
 echo "Installation complete"
 
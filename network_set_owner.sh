@@ -3,6 +3,6 @@
 . ./variables.sh
 
 if ! [ -z ${OWNER+x} ]; then
-    echo "Setting canister owner"
-    eval dfx canister --network ic call token set_owner "'(principal $OWNER)'"
+    echo "Proposing canister owner, $OWNER has to call accept_ownership within 7 days"
+    eval dfx canister --network ic call token propose_owner "'(principal $OWNER)'"
 fi
@@ -4,6 +4,19 @@ PUBLIC_KEY="principal \"$( \
     dfx identity get-principal
 )\""
 
+. ./variables.sh
+
+#Upgrade keeps the current proxy owner when PROXY_OWNER is not set, upgrading identity must be a controller of the canister.
+#Setting PROXY_OWNER replaces the owner, use it to recover a lost owner or to set the owner of proxies installed without one,
+#proxies installed before owners were added get the upgrading identity as owner otherwise.
+if [ -z ${PROXY_OWNER+x} ]; then
+    PROXY_ARG="(null)"
+else
+    PROXY_ARG="(opt principal $PROXY_OWNER)"
+fi
+
 dfx build --network ic ledger_proxy
 
-eval dfx canister --network ic install ledger_proxy -m upgrade
\ No newline at end of file
+eval dfx canister --network ic install ledger_proxy --argument="'$PROXY_ARG'" -m upgrade
+
+echo "Upgrade complete"
@@ -5,4 +5,5 @@ TICKER="\"Project Ticker\""
 DESCRIPTION="\"Project Description\""
 SIZE=10000 # Number of tokens
 
-# OWNER=principal # Used to set owner of the token canister
\ No newline at end of file
+# OWNER=principal # Used to set owner of the token canister
+# PROXY_OWNER=principal # Used to set owner of the ledger proxy, defaults to the deploying identity
\ No newline at end of file